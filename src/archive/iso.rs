@@ -0,0 +1,372 @@
+//! ISO 9660 member table read from the volume descriptors and directory
+//! records.
+//!
+//! Rock Ridge names are preferred, then Joliet, then plain ISO 9660 names
+//! with the `;1` version stripped.
+
+use super::{read_at, Data, Member};
+use crate::client::ListingBackend;
+use crate::listing::invalid_data;
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use std::{
+    collections::HashSet,
+    convert::TryInto,
+    io,
+    path::{Path, PathBuf},
+};
+
+const SECTOR: u64 = 2048;
+/// Volume descriptors start after the system area.
+const FIRST_DESCRIPTOR: u64 = 16;
+/// Volume descriptors fetched at once.
+const DESCRIPTORS: usize = 16;
+const PRIMARY: u8 = 1;
+const SUPPLEMENTARY: u8 = 2;
+const TERMINATOR: u8 = 255;
+const ROOT_RECORD: usize = 156;
+const FLAG_DIRECTORY: u8 = 0x02;
+
+fn u32_at(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap())
+}
+
+/// Which set of names to read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Names {
+    Iso,
+    Joliet,
+    RockRidge,
+}
+
+/// Directory record, one per file or directory.
+struct Record {
+    extent: u64,
+    size: u64,
+    flags: u8,
+    mtime: Option<DateTime<Utc>>,
+    /// File identifier, `None` for `.` and `..`.
+    id: Option<Vec<u8>>,
+    system_use: Vec<u8>,
+}
+
+impl Record {
+    fn parse(buf: &[u8]) -> Option<Self> {
+        let len = *buf.first()? as usize;
+        if len < 34 || len > buf.len() {
+            return None;
+        }
+        let id_len = buf[32] as usize;
+        if 33 + id_len > len {
+            return None;
+        }
+        let id = &buf[33..33 + id_len];
+        // The identifier is padded to an even offset.
+        let system_use = 33 + id_len + (1 - id_len % 2);
+        Some(Self {
+            extent: u32_at(buf, 2) as u64,
+            size: u32_at(buf, 10) as u64,
+            flags: buf[25],
+            mtime: record_time(&buf[18..25]),
+            id: if id == [0] || id == [1] {
+                None
+            } else {
+                Some(id.to_vec())
+            },
+            system_use: buf[system_use.min(len)..len].to_vec(),
+        })
+    }
+
+    fn is_dir(&self) -> bool {
+        self.flags & FLAG_DIRECTORY != 0
+    }
+}
+
+/// Recording date of a directory record, the last byte is the offset from
+/// GMT in 15 minute steps.
+fn record_time(buf: &[u8]) -> Option<DateTime<Utc>> {
+    let date = NaiveDate::from_ymd_opt(1900 + buf[0] as i32, buf[1] as u32, buf[2] as u32)?;
+    let time = date.and_hms_opt(buf[3] as u32, buf[4] as u32, buf[5] as u32)?;
+    let offset = Duration::minutes(buf[6] as i8 as i64 * 15);
+    Some(Utc.from_utc_datetime(&(time - offset)))
+}
+
+/// Plain ISO 9660 or Joliet name of a record.
+fn record_name(id: &[u8], names: Names) -> String {
+    let name = match names {
+        Names::Joliet => {
+            let units: Vec<u16> = id
+                .chunks_exact(2)
+                .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(id).into_owned(),
+    };
+    let name = match name.rfind(';') {
+        Some(version) => &name[..version],
+        None => &name,
+    };
+    name.trim_end_matches('.').to_string()
+}
+
+/// Rock Ridge alternate name from SUSP entries in `area`, along with the
+/// continuation area location if there is one.
+fn rock_ridge_name(area: &[u8], name: &mut Vec<u8>) -> Option<(u64, u64, u64)> {
+    let mut continuation = None;
+    let mut pos = 0;
+    while pos + 4 <= area.len() {
+        let len = area[pos + 2] as usize;
+        if len < 4 || pos + len > area.len() {
+            break;
+        }
+        let entry = &area[pos..pos + len];
+        match &entry[..2] {
+            b"NM" if len >= 5 => name.extend_from_slice(&entry[5..]),
+            b"CE" if len >= 28 => {
+                continuation = Some((
+                    u32_at(entry, 4) as u64,
+                    u32_at(entry, 12) as u64,
+                    u32_at(entry, 20) as u64,
+                ))
+            }
+            b"ST" => break,
+            _ => {}
+        }
+        pos += len;
+    }
+    continuation
+}
+
+/// Rock Ridge alternate name of a record with the system use area `area`,
+/// following continuation areas in the image at `path` of `size` bytes.
+async fn alternate_name(
+    backend: &dyn ListingBackend,
+    path: &Path,
+    size: u64,
+    area: &[u8],
+) -> io::Result<Vec<u8>> {
+    let mut name = Vec::new();
+    let mut continuation = rock_ridge_name(area, &mut name);
+    // Broken images may loop or point past the end, every area is read once.
+    let mut visited = HashSet::new();
+    while let Some((block, offset, len)) = continuation.take() {
+        let start = block * SECTOR + offset;
+        if !visited.insert((block, offset)) || start + len > size {
+            break;
+        }
+        let area = read_at(backend, path, start, len as usize).await?;
+        continuation = rock_ridge_name(&area, &mut name);
+    }
+    Ok(name)
+}
+
+/// Read the volume descriptors and pick the root record and names to use.
+async fn root(backend: &dyn ListingBackend, path: &Path, size: u64) -> io::Result<(Record, Names)> {
+    let first = FIRST_DESCRIPTOR * SECTOR;
+    let count = ((size.saturating_sub(first)) / SECTOR).min(DESCRIPTORS as u64) as usize;
+    let descriptors = read_at(backend, path, first, count * SECTOR as usize).await?;
+    let mut primary = None;
+    let mut joliet = None;
+    for descriptor in descriptors.chunks_exact(SECTOR as usize) {
+        if &descriptor[1..6] != b"CD001" {
+            break;
+        }
+        match descriptor[0] {
+            PRIMARY => primary = Record::parse(&descriptor[ROOT_RECORD..]),
+            // Joliet announces UCS-2 with one of three escape sequences.
+            SUPPLEMENTARY if matches!(&descriptor[88..91], b"%/@" | b"%/C" | b"%/E") => {
+                joliet = Record::parse(&descriptor[ROOT_RECORD..])
+            }
+            TERMINATOR => break,
+            _ => {}
+        }
+    }
+    let primary = primary
+        .ok_or_else(|| invalid_data(format!("'{}' is not an ISO 9660 image", path.display())))?;
+
+    // Rock Ridge starts with an `SP` entry in the `.` record of the root.
+    let root_dir = read_at(backend, path, primary.extent * SECTOR, SECTOR as usize).await?;
+    let rock_ridge = Record::parse(&root_dir).is_some_and(|dot| dot.system_use.starts_with(b"SP"));
+    Ok(match (rock_ridge, joliet) {
+        (true, _) => (primary, Names::RockRidge),
+        (false, Some(joliet)) => (joliet, Names::Joliet),
+        (false, None) => (primary, Names::Iso),
+    })
+}
+
+/// Read members of the ISO 9660 image at `path` of `size` bytes.
+pub async fn members(
+    backend: &dyn ListingBackend,
+    path: &Path,
+    size: u64,
+) -> io::Result<Vec<(PathBuf, Member)>> {
+    let (root, names) = root(backend, path, size).await?;
+    let mut members: Vec<(PathBuf, Member)> = Vec::new();
+    let mut visited = HashSet::new();
+    let mut dirs = vec![(PathBuf::new(), root.extent, root.size)];
+    while let Some((dir, extent, length)) = dirs.pop() {
+        // Broken images may loop, every extent is read once.
+        if !visited.insert(extent) || extent * SECTOR + length > size {
+            continue;
+        }
+        let data = read_at(backend, path, extent * SECTOR, length as usize).await?;
+        for sector in data.chunks(SECTOR as usize) {
+            // Records never cross sectors, a zero length pads the rest.
+            let mut pos = 0;
+            while let Some(record) = Record::parse(&sector[pos..]) {
+                pos += sector[pos] as usize;
+                let id = match &record.id {
+                    Some(id) => id,
+                    None => continue,
+                };
+                let name = if names == Names::RockRidge {
+                    alternate_name(backend, path, size, &record.system_use).await?
+                } else {
+                    Vec::new()
+                };
+                let name = if name.is_empty() {
+                    record_name(id, names)
+                } else {
+                    String::from_utf8_lossy(&name).into_owned()
+                };
+                let member_path = dir.join(&name);
+                if record.is_dir() {
+                    dirs.push((member_path.clone(), record.extent, record.size));
+                }
+
+                // Parts of multi-extent files follow each other.
+                let offset = record.extent * SECTOR;
+                if let Some((last_path, last)) = members.last_mut() {
+                    if *last_path == member_path {
+                        if let Data::Stored(start) = last.data {
+                            if start + last.size == offset {
+                                last.size += record.size;
+                                continue;
+                            }
+                        }
+                        warn!("Skipping fragmented '{}' of '{}'", name, path.display());
+                        continue;
+                    }
+                }
+                members.push((
+                    member_path,
+                    Member {
+                        is_dir: record.is_dir(),
+                        mtime: record.mtime,
+                        size: if record.is_dir() { 0 } else { record.size },
+                        data: Data::Stored(offset),
+                    },
+                ));
+            }
+        }
+    }
+    debug!(
+        "Found {} members in '{}' with {:?} names",
+        members.len(),
+        path.display(),
+        names
+    );
+    Ok(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::tests::Bytes;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    /// Members of a gzipped test image. The images hold the same tree under
+    /// Rock Ridge, Joliet or plain names and list the same with `bsdtar`.
+    async fn members_of(gz: &[u8]) -> (Vec<u8>, Vec<(PathBuf, Member)>) {
+        let mut image = Vec::new();
+        GzDecoder::new(gz).read_to_end(&mut image).unwrap();
+        let backend = Bytes::new("disc.iso", &image);
+        let mut members = members(&backend, Path::new("/disc.iso"), image.len() as u64)
+            .await
+            .unwrap();
+        members.sort_by(|a, b| a.0.cmp(&b.0));
+        (image, members)
+    }
+
+    fn check(image: &[u8], members: &[(PathBuf, Member)], names: [&str; 3]) {
+        // 10:16:06 at GMT+2.
+        let mtime = Utc.with_ymd_and_hms(2023, 4, 2, 8, 16, 6).single();
+        let summary: Vec<_> = members
+            .iter()
+            .map(|(path, m)| (path.to_str().unwrap(), m.is_dir, m.size, m.mtime))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (names[0], true, 0, mtime),
+                (names[1], false, 3000, mtime),
+                (names[2], false, 8, mtime),
+            ]
+        );
+        match members[1].1.data {
+            Data::Stored(start) => {
+                let start = start as usize;
+                assert_eq!(
+                    &image[start..start + 3000],
+                    "track one\n".repeat(300).as_bytes()
+                );
+            }
+            data => panic!("Unexpected {:?}", data),
+        }
+    }
+
+    #[tokio::test]
+    async fn rock_ridge_names() {
+        let (image, members) = members_of(include_bytes!("testdata/rockridge.iso.gz")).await;
+        check(
+            &image,
+            &members,
+            ["Disc 1", "Disc 1/01 - Track One.txt", "Read Me.txt"],
+        );
+    }
+
+    #[tokio::test]
+    async fn continuation_loop() {
+        // `NM` of "ab" followed by a `CE` pointing back at itself at the
+        // start of the second sector.
+        let mut area = vec![b'N', b'M', 7, 1, 0, b'a', b'b'];
+        area.extend_from_slice(&[b'C', b'E', 28, 1]);
+        for value in [1u32, 0, area.len() as u32 + 24] {
+            area.extend_from_slice(&value.to_le_bytes());
+            area.extend_from_slice(&value.to_be_bytes());
+        }
+        let mut image = vec![0; SECTOR as usize];
+        image.extend_from_slice(&area);
+        let backend = Bytes::new("loop.iso", &image);
+        let path = Path::new("/loop.iso");
+        let name = alternate_name(&backend, path, image.len() as u64, &area)
+            .await
+            .unwrap();
+        assert_eq!(name, b"abab");
+
+        // Areas past the end of the image are not asked for.
+        let name = alternate_name(&backend, path, SECTOR, &area).await.unwrap();
+        assert_eq!(name, b"ab");
+    }
+
+    #[tokio::test]
+    async fn joliet_names() {
+        let (image, members) = members_of(include_bytes!("testdata/joliet.iso.gz")).await;
+        check(
+            &image,
+            &members,
+            ["Disc 1", "Disc 1/01 - Track One.txt", "Read Me.txt"],
+        );
+    }
+
+    #[tokio::test]
+    async fn iso_names() {
+        let (image, members) = members_of(include_bytes!("testdata/plain.iso.gz")).await;
+        check(
+            &image,
+            &members,
+            ["DISC1", "DISC1/TRACK01.TXT", "README.TXT"],
+        );
+    }
+}
@@ -0,0 +1,677 @@
+//! Archives on the server browsed as directories.
+//!
+//! Member tables are read with range requests from the archive itself and
+//! members are served by fetching only their byte ranges, so browsing a
+//! large archive never downloads it whole.
+
+mod iso;
+mod tar;
+mod zip;
+
+use crate::client::{Changed, Listing, ListingBackend, RemoteEntry, Validators};
+use crate::listing::{invalid_data, remote_entry};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use flate2::{Decompress, FlushDecompress, Status};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::Mutex as AsyncMutex;
+
+/// Compressed bytes fetched at once while inflating a member.
+const INFLATE_CHUNK: usize = 1024 * 1024;
+
+/// Inflate streams kept per archive, the least recently read go first.
+const INFLATERS: usize = 4;
+
+/// How archives show up in listings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArchiveMode {
+    /// Archives are plain files.
+    #[default]
+    Off,
+    /// `foo.zip` is a directory instead of a file.
+    Replace,
+    /// `foo.tar` stays a file and `foo.tar#` is the directory.
+    Alongside,
+}
+
+impl FromStr for ArchiveMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(ArchiveMode::Off),
+            "replace" => Ok(ArchiveMode::Replace),
+            "alongside" => Ok(ArchiveMode::Alongside),
+            _ => Err(format!("Unknown archive mode '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Zip,
+    Tar,
+    Iso,
+}
+
+impl Format {
+    fn of(name: &str) -> Option<Self> {
+        let name = name.to_lowercase();
+        if name.ends_with(".zip") {
+            Some(Format::Zip)
+        } else if name.ends_with(".tar") {
+            Some(Format::Tar)
+        } else if name.ends_with(".iso") {
+            Some(Format::Iso)
+        } else {
+            None
+        }
+    }
+}
+
+/// File or directory inside an archive.
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub is_dir: bool,
+    pub mtime: Option<DateTime<Utc>>,
+    pub size: u64,
+    pub data: Data,
+}
+
+/// Where the bytes of a member are in the archive.
+#[derive(Debug, Clone, Copy)]
+pub enum Data {
+    /// Stored as is at the offset.
+    Stored(u64),
+    /// ZIP member after its local header at `header`.
+    Zip {
+        header: u64,
+        method: u16,
+        compressed: u64,
+    },
+}
+
+/// Member table of an opened archive.
+#[derive(Debug, Default)]
+struct Archive {
+    /// Listings of directories by their path inside the archive.
+    dirs: HashMap<PathBuf, HashMap<String, RemoteEntry>>,
+    files: HashMap<PathBuf, Member>,
+    /// Data offsets of ZIP members resolved so far.
+    offsets: Mutex<HashMap<PathBuf, u64>>,
+    /// Inflate streams of deflated members continued by sequential reads,
+    /// the most recently read last.
+    inflaters: Mutex<Vec<(PathBuf, Arc<AsyncMutex<Inflater>>)>>,
+}
+
+impl Archive {
+    fn new(members: Vec<(PathBuf, Member)>) -> Self {
+        let mut archive = Archive::default();
+        archive.dirs.insert(PathBuf::from("/"), HashMap::new());
+        for (path, member) in members {
+            // Absolute and `..` components must not escape the archive.
+            let path = Path::new("/").join(
+                path.components()
+                    .filter(|c| matches!(c, Component::Normal(_)))
+                    .collect::<PathBuf>(),
+            );
+            let name = match path.file_name() {
+                Some(name) => name.to_string_lossy().into_owned(),
+                None => continue,
+            };
+            let size = if member.is_dir {
+                None
+            } else {
+                Some(member.size)
+            };
+            let entry = remote_entry(name.clone(), member.is_dir, member.mtime, size);
+            let parent = path.parent().unwrap().to_path_buf();
+            archive.add_parents(&parent);
+            archive.dirs.entry(parent).or_default().insert(name, entry);
+            if member.is_dir {
+                archive.dirs.entry(path).or_default();
+            } else {
+                archive.files.insert(path, member);
+            }
+        }
+        archive
+    }
+
+    /// Make sure `dir` and all its parents are listed, archives often skip
+    /// entries of directories.
+    fn add_parents(&mut self, dir: &Path) {
+        let mut dir = dir.to_path_buf();
+        while let (Some(parent), Some(name)) = (dir.parent(), dir.file_name()) {
+            let name = name.to_string_lossy().into_owned();
+            let parent = parent.to_path_buf();
+            self.dirs
+                .entry(parent.clone())
+                .or_default()
+                .entry(name.clone())
+                .or_insert_with(|| remote_entry(name, true, None, None));
+            self.dirs.entry(dir).or_default();
+            dir = parent;
+        }
+    }
+
+    /// Offset of the data of the ZIP member `inner`.
+    async fn zip_offset(
+        &self,
+        backend: &dyn ListingBackend,
+        remote: &Path,
+        inner: &Path,
+        header: u64,
+    ) -> io::Result<u64> {
+        if let Some(offset) = self.offsets.lock().unwrap().get(inner) {
+            return Ok(*offset);
+        }
+        let offset = zip::data_offset(backend, remote, header).await?;
+        self.offsets
+            .lock()
+            .unwrap()
+            .insert(inner.to_path_buf(), offset);
+        Ok(offset)
+    }
+
+    /// Inflate stream of the member `inner`, a new one if it has none.
+    fn inflater(&self, inner: &Path) -> Arc<AsyncMutex<Inflater>> {
+        let mut inflaters = self.inflaters.lock().unwrap();
+        let inflater = match inflaters.iter().position(|(path, _)| path == inner) {
+            Some(pos) => inflaters.remove(pos).1,
+            None => Arc::new(AsyncMutex::new(Inflater::new())),
+        };
+        inflaters.push((inner.to_path_buf(), inflater.clone()));
+        if inflaters.len() > INFLATERS {
+            inflaters.remove(0);
+        }
+        inflater
+    }
+
+    async fn read(
+        &self,
+        backend: &dyn ListingBackend,
+        remote: &Path,
+        inner: &Path,
+        size: usize,
+        offset: usize,
+    ) -> io::Result<Vec<u8>> {
+        let member = self.files.get(inner).ok_or_else(|| not_found(inner))?;
+        let offset = offset as u64;
+        if offset >= member.size {
+            return Ok(Vec::new());
+        }
+        let size = size.min((member.size - offset) as usize);
+        let remote_read =
+            |start: u64| backend.read(remote.to_path_buf(), size, (start + offset) as usize);
+        match member.data {
+            Data::Stored(start) => remote_read(start).await,
+            Data::Zip {
+                header,
+                method,
+                compressed,
+            } => {
+                let start = self.zip_offset(backend, remote, inner, header).await?;
+                match method {
+                    zip::STORED => remote_read(start).await,
+                    zip::DEFLATED => {
+                        let inflater = self.inflater(inner);
+                        let data = inflater
+                            .lock()
+                            .await
+                            .read(backend, remote, (start, compressed), offset, size)
+                            .await;
+                        // Nothing is left to continue once the member ends.
+                        if data.is_err() || offset + size as u64 >= member.size {
+                            let mut inflaters = self.inflaters.lock().unwrap();
+                            inflaters.retain(|(_, kept)| !Arc::ptr_eq(kept, &inflater));
+                        }
+                        data
+                    }
+                    method => Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!("Unsupported ZIP compression method {}", method),
+                    )),
+                }
+            }
+        }
+    }
+}
+
+/// Deflate stream of a member, positioned where the last read ended.
+struct Inflater {
+    stream: Decompress,
+    /// Compressed bytes fetched but not consumed yet.
+    input: Vec<u8>,
+    /// Compressed bytes fetched so far.
+    fetched: u64,
+    /// Uncompressed bytes produced so far.
+    out: u64,
+}
+
+impl std::fmt::Debug for Inflater {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Inflater")
+            .field("fetched", &self.fetched)
+            .field("out", &self.out)
+            .finish()
+    }
+}
+
+impl Inflater {
+    fn new() -> Self {
+        Self {
+            stream: Decompress::new(false),
+            input: Vec::new(),
+            fetched: 0,
+            out: 0,
+        }
+    }
+
+    /// Inflate `size` bytes at `offset` of the member whose compressed data
+    /// takes `(start, length)` of the archive at `remote`. Reads going
+    /// backwards start over from the beginning of the member.
+    async fn read(
+        &mut self,
+        backend: &dyn ListingBackend,
+        remote: &Path,
+        (start, length): (u64, u64),
+        offset: u64,
+        size: usize,
+    ) -> io::Result<Vec<u8>> {
+        if offset < self.out {
+            *self = Self::new();
+        }
+        let end = offset + size as u64;
+        let mut result = Vec::with_capacity(size);
+        let mut buf = vec![0; 64 * 1024];
+        let mut stalled = false;
+        while self.out < end {
+            // All input may be fetched with output still held by the stream.
+            if (self.input.is_empty() || stalled) && self.fetched < length {
+                let chunk = (length - self.fetched).min(INFLATE_CHUNK as u64) as usize;
+                let fetched = backend
+                    .read(remote.to_path_buf(), chunk, (start + self.fetched) as usize)
+                    .await?;
+                if fetched.is_empty() {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+                self.fetched += fetched.len() as u64;
+                self.input.extend(fetched);
+            } else if stalled {
+                return Err(invalid_data("Truncated deflate stream"));
+            }
+            // Nothing past `end` is inflated, the next sequential read
+            // carries on right there.
+            let limit = (end - self.out).min(buf.len() as u64) as usize;
+            let (total_in, total_out) = (self.stream.total_in(), self.stream.total_out());
+            let status = self
+                .stream
+                .decompress(&self.input, &mut buf[..limit], FlushDecompress::None)
+                .map_err(invalid_data)?;
+            let consumed = (self.stream.total_in() - total_in) as usize;
+            let produced = (self.stream.total_out() - total_out) as usize;
+            self.input.drain(..consumed);
+            let from = offset.saturating_sub(self.out).min(produced as u64) as usize;
+            let to = (end - self.out).min(produced as u64) as usize;
+            result.extend_from_slice(&buf[from..to]);
+            self.out += produced as u64;
+            if status == Status::StreamEnd {
+                break;
+            }
+            stalled = consumed == 0 && produced == 0;
+        }
+        Ok(result)
+    }
+}
+
+/// Exactly `size` bytes at `offset` of the archive at `path`.
+async fn read_at(
+    backend: &dyn ListingBackend,
+    path: &Path,
+    offset: u64,
+    size: usize,
+) -> io::Result<Vec<u8>> {
+    let buf = backend
+        .read(path.to_path_buf(), size, offset as usize)
+        .await?;
+    if buf.len() < size {
+        return Err(invalid_data(format!(
+            "Short read of '{}' at {}",
+            path.display(),
+            offset
+        )));
+    }
+    Ok(buf)
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("No '{}' in the archive", path.display()),
+    )
+}
+
+#[derive(Debug)]
+pub struct Archives {
+    inner: Arc<dyn ListingBackend>,
+    mode: ArchiveMode,
+    /// Remote path and size of listed archives by the mount path of their
+    /// directory.
+    known: Mutex<HashMap<PathBuf, (PathBuf, Option<u64>)>>,
+    /// Member tables by the remote path of the archive.
+    opened: Mutex<HashMap<PathBuf, Arc<Archive>>>,
+}
+
+impl Archives {
+    pub fn new(inner: Arc<dyn ListingBackend>, mode: ArchiveMode) -> Self {
+        info!("Browsing archives, {:?} mode", mode);
+        Self {
+            inner,
+            mode,
+            known: Mutex::new(HashMap::new()),
+            opened: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Remote path and size of the archive holding `path` along with the
+    /// path inside it.
+    fn resolve(&self, path: &Path) -> Option<(PathBuf, Option<u64>, PathBuf)> {
+        let known = self.known.lock().unwrap();
+        path.ancestors().find_map(|dir| {
+            let (remote, size) = known.get(dir)?;
+            let inner = Path::new("/").join(path.strip_prefix(dir).ok()?);
+            Some((remote.clone(), *size, inner))
+        })
+    }
+
+    /// Member table of the archive at `remote`, read on first use.
+    async fn open(&self, remote: &Path, size: Option<u64>) -> io::Result<Arc<Archive>> {
+        if let Some(archive) = self.opened.lock().unwrap().get(remote) {
+            return Ok(archive.clone());
+        }
+        let size = match size {
+            Some(size) => size,
+            None => self
+                .inner
+                .stat(remote.to_path_buf())
+                .await?
+                .size
+                .ok_or_else(|| {
+                    invalid_data(format!("Size of '{}' is unknown", remote.display()))
+                })?,
+        };
+        let name = remote.to_string_lossy();
+        let members = match Format::of(&name) {
+            Some(Format::Zip) => zip::members(&*self.inner, remote, size).await?,
+            Some(Format::Tar) => tar::members(&*self.inner, remote, size).await?,
+            Some(Format::Iso) => iso::members(&*self.inner, remote, size).await?,
+            None => return Err(not_found(remote)),
+        };
+        let archive = Arc::new(Archive::new(members));
+        self.opened
+            .lock()
+            .unwrap()
+            .insert(remote.to_path_buf(), archive.clone());
+        Ok(archive)
+    }
+
+    /// Turn archives among `entries` of the remote directory `path` into
+    /// directories according to the mode.
+    fn expose(&self, path: &Path, entries: &mut Vec<RemoteEntry>) {
+        let mut known = self.known.lock().unwrap();
+        let mut opened = self.opened.lock().unwrap();
+        let mut added = Vec::new();
+        for entry in entries.iter_mut() {
+            let name = entry.name.clone().unwrap_or_default();
+            if entry.r#type.as_deref() == Some("directory") || Format::of(&name).is_none() {
+                continue;
+            }
+            let remote = path.join(&name);
+            let size = entry.size;
+            let mut dir = entry.clone();
+            dir.r#type = Some(String::from("directory"));
+            dir.size = None;
+            dir.etag = None;
+            let old = match self.mode {
+                ArchiveMode::Alongside => {
+                    let name = format!("{}#", name);
+                    dir.name = Some(name.clone());
+                    added.push(dir);
+                    known.insert(path.join(&name), (remote.clone(), size))
+                }
+                _ => {
+                    *entry = dir;
+                    known.insert(remote.clone(), (remote.clone(), size))
+                }
+            };
+            // An archive changed on the server is opened again.
+            if old.is_some_and(|(_, old)| old != size) {
+                opened.remove(&remote);
+            }
+        }
+        entries.extend(added);
+    }
+}
+
+#[async_trait]
+impl ListingBackend for Archives {
+    async fn list(&self, path: PathBuf) -> io::Result<Vec<RemoteEntry>> {
+        if let Some((remote, size, inner)) = self.resolve(&path) {
+            let archive = self.open(&remote, size).await?;
+            return match archive.dirs.get(&inner) {
+                Some(dir) => Ok(dir.values().cloned().collect()),
+                None => Err(not_found(&inner)),
+            };
+        }
+        let mut entries = self.inner.list(path.clone()).await?;
+        self.expose(&path, &mut entries);
+        Ok(entries)
+    }
+
+    async fn list_changed(&self, path: PathBuf, validators: &Validators) -> io::Result<Changed> {
+        if self.resolve(&path).is_some() {
+            return Ok(Some(Listing::full(self.list(path).await?)));
+        }
+        let mut listing = self.inner.list_changed(path.clone(), validators).await?;
+        if let Some(listing) = &mut listing {
+            self.expose(&path, &mut listing.entries);
+        }
+        Ok(listing)
+    }
+
+    async fn read(&self, path: PathBuf, size: usize, offset: usize) -> io::Result<Vec<u8>> {
+        match self.resolve(&path) {
+            Some((remote, archive_size, inner)) => {
+                let archive = self.open(&remote, archive_size).await?;
+                archive
+                    .read(&*self.inner, &remote, &inner, size, offset)
+                    .await
+            }
+            None => self.inner.read(path, size, offset).await,
+        }
+    }
+
+    async fn stat(&self, path: PathBuf) -> io::Result<RemoteEntry> {
+        match self.resolve(&path) {
+            Some((remote, size, inner)) => {
+                let archive = self.open(&remote, size).await?;
+                let parent = inner.parent().unwrap_or(&inner);
+                let name = inner.file_name().unwrap_or_default().to_string_lossy();
+                archive
+                    .dirs
+                    .get(parent)
+                    .and_then(|dir| dir.get(name.as_ref()))
+                    .cloned()
+                    .ok_or_else(|| not_found(&inner))
+            }
+            None => self.inner.stat(path).await,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use flate2::{write::DeflateEncoder, Compression};
+    use std::{
+        io::Write,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// Server holding a single file `name` at the root.
+    #[derive(Debug)]
+    pub struct Bytes {
+        name: String,
+        data: Vec<u8>,
+        pub reads: AtomicUsize,
+    }
+
+    impl Bytes {
+        pub fn new(name: &str, data: &[u8]) -> Self {
+            Self {
+                name: name.to_string(),
+                data: data.to_vec(),
+                reads: AtomicUsize::new(0),
+            }
+        }
+
+        fn entry(&self) -> RemoteEntry {
+            remote_entry(self.name.clone(), false, None, Some(self.data.len() as u64))
+        }
+    }
+
+    #[async_trait]
+    impl ListingBackend for Bytes {
+        async fn list(&self, _: PathBuf) -> io::Result<Vec<RemoteEntry>> {
+            Ok(vec![self.entry()])
+        }
+
+        async fn read(&self, _: PathBuf, size: usize, offset: usize) -> io::Result<Vec<u8>> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            let offset = offset.min(self.data.len());
+            let end = (offset + size).min(self.data.len());
+            Ok(self.data[offset..end].to_vec())
+        }
+
+        async fn stat(&self, _: PathBuf) -> io::Result<RemoteEntry> {
+            Ok(self.entry())
+        }
+    }
+
+    #[tokio::test]
+    async fn inflate_sequential_reads() {
+        let plain: Vec<u8> = (0..300_000u32)
+            .flat_map(|i| format!("{} ", i * 7919 % 10007).into_bytes())
+            .collect();
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&plain).unwrap();
+        let deflated = encoder.finish().unwrap();
+        assert!(deflated.len() < INFLATE_CHUNK);
+        let backend = Bytes::new("member", &deflated);
+
+        let mut inflater = Inflater::new();
+        let mut inflated = Vec::new();
+        while inflated.len() < plain.len() {
+            let size = 100_000.min(plain.len() - inflated.len());
+            let offset = inflated.len() as u64;
+            let chunk = inflater
+                .read(
+                    &backend,
+                    Path::new("/member"),
+                    (0, deflated.len() as u64),
+                    offset,
+                    size,
+                )
+                .await
+                .unwrap();
+            assert_eq!(chunk.len(), size);
+            inflated.extend(chunk);
+        }
+        assert!(inflated == plain);
+        // The stream is never started over.
+        assert_eq!(backend.reads.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn inflaters_are_bounded() {
+        let plain = b"la ".repeat(1000);
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&plain).unwrap();
+        let deflated = encoder.finish().unwrap();
+        let backend = Bytes::new("members.zip", &deflated);
+        let member = Member {
+            is_dir: false,
+            mtime: None,
+            size: plain.len() as u64,
+            data: Data::Zip {
+                header: 0,
+                method: zip::DEFLATED,
+                compressed: deflated.len() as u64,
+            },
+        };
+        let paths: Vec<PathBuf> = (0..INFLATERS + 2)
+            .map(|i| PathBuf::from(format!("/{}.txt", i)))
+            .collect();
+        let archive = Archive::new(paths.iter().map(|p| (p.clone(), member.clone())).collect());
+        for path in &paths {
+            archive.offsets.lock().unwrap().insert(path.clone(), 0);
+        }
+        let remote = Path::new("/members.zip");
+        for path in &paths {
+            let data = archive.read(&backend, remote, path, 3, 3).await.unwrap();
+            assert_eq!(data, b"la ");
+        }
+        assert_eq!(archive.inflaters.lock().unwrap().len(), INFLATERS);
+
+        // The stream of a member read to its end is dropped.
+        let last = paths.last().unwrap();
+        let data = archive
+            .read(&backend, remote, last, 100, 2997)
+            .await
+            .unwrap();
+        assert_eq!(data, b"la ");
+        let inflaters = archive.inflaters.lock().unwrap();
+        assert_eq!(inflaters.len(), INFLATERS - 1);
+        assert!(inflaters.iter().all(|(path, _)| path != last));
+    }
+
+    #[tokio::test]
+    async fn zip_as_directory() {
+        let zip = include_bytes!("testdata/sample.zip");
+        let archives = Archives::new(
+            Arc::new(Bytes::new("sample.zip", zip)),
+            ArchiveMode::Replace,
+        );
+        let root = archives.list(PathBuf::from("/")).await.unwrap();
+        assert_eq!(root[0].name.as_deref(), Some("sample.zip"));
+        assert_eq!(root[0].r#type.as_deref(), Some("directory"));
+
+        let mut album = archives
+            .list(PathBuf::from("/sample.zip/album"))
+            .await
+            .unwrap();
+        album.sort_by(|a, b| a.name.cmp(&b.name));
+        let names: Vec<_> = album.iter().map(|e| (e.name.as_deref(), e.size)).collect();
+        assert_eq!(
+            names,
+            vec![(Some("01.txt"), Some(6)), (Some("notes.txt"), Some(3000))]
+        );
+
+        let stored = archives
+            .read(PathBuf::from("/sample.zip/album/01.txt"), 100, 0)
+            .await
+            .unwrap();
+        assert_eq!(stored, b"hello\n");
+        let deflated = archives
+            .read(PathBuf::from("/sample.zip/album/notes.txt"), 9, 2991)
+            .await
+            .unwrap();
+        assert_eq!(deflated, b"la la la ");
+    }
+}
@@ -0,0 +1,259 @@
+//! TAR member table read header by header from the start of the file.
+//!
+//! Plain ustar, GNU long names and pax extended headers are understood.
+//! Headers are fetched in windows so archives of small files don't cost a
+//! request per member.
+
+use super::{Data, Member};
+use crate::client::ListingBackend;
+use crate::listing::invalid_data;
+use chrono::{DateTime, TimeZone, Utc};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+const BLOCK: u64 = 512;
+/// Bytes fetched at once while walking the headers.
+const WINDOW: u64 = 64 * 1024;
+
+/// Part of the archive fetched last.
+struct Window {
+    start: u64,
+    buf: Vec<u8>,
+}
+
+impl Window {
+    /// `len` bytes at `pos` of the archive at `path` of `size` bytes.
+    async fn get(
+        &mut self,
+        backend: &dyn ListingBackend,
+        path: &Path,
+        size: u64,
+        pos: u64,
+        len: u64,
+    ) -> io::Result<Vec<u8>> {
+        let end = self.start + self.buf.len() as u64;
+        if pos < self.start || pos + len > end {
+            let fetch = len.max(WINDOW).min(size - pos);
+            self.buf = backend
+                .read(path.to_path_buf(), fetch as usize, pos as usize)
+                .await?;
+            self.start = pos;
+            if (self.buf.len() as u64) < len {
+                return Err(invalid_data(format!(
+                    "Short read of '{}' at {}",
+                    path.display(),
+                    pos
+                )));
+            }
+        }
+        let from = (pos - self.start) as usize;
+        Ok(self.buf[from..from + len as usize].to_vec())
+    }
+}
+
+/// Octal number field, or base-256 when the high bit of the first byte is set.
+fn number(field: &[u8]) -> u64 {
+    if field.first().is_some_and(|b| b & 0x80 != 0) {
+        return field[1..]
+            .iter()
+            .fold((field[0] & 0x7f) as u64, |n, b| (n << 8) | *b as u64);
+    }
+    let text = String::from_utf8_lossy(field);
+    u64::from_str_radix(text.trim_matches(|c| c == '\0' || c == ' '), 8).unwrap_or(0)
+}
+
+/// NUL terminated string field.
+fn text(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn checksum_ok(header: &[u8]) -> bool {
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, b)| {
+            if (148..156).contains(&i) {
+                b' ' as u64
+            } else {
+                *b as u64
+            }
+        })
+        .sum();
+    sum == number(&header[148..156])
+}
+
+/// Records of a pax extended header, `"<len> <key>=<value>\n"` each.
+fn pax_records(data: &[u8]) -> HashMap<String, String> {
+    let mut records = HashMap::new();
+    let mut rest = data;
+    while let Some(space) = rest.iter().position(|b| *b == b' ') {
+        let len: usize = match String::from_utf8_lossy(&rest[..space]).parse() {
+            Ok(len) if len > space && len <= rest.len() => len,
+            _ => break,
+        };
+        let record = String::from_utf8_lossy(&rest[space + 1..len]);
+        if let Some((key, value)) = record.trim_end_matches('\n').split_once('=') {
+            records.insert(key.to_string(), value.to_string());
+        }
+        rest = &rest[len..];
+    }
+    records
+}
+
+fn round_up(size: u64) -> u64 {
+    size.div_ceil(BLOCK) * BLOCK
+}
+
+/// Read members of the TAR archive at `path` of `size` bytes.
+pub async fn members(
+    backend: &dyn ListingBackend,
+    path: &Path,
+    size: u64,
+) -> io::Result<Vec<(PathBuf, Member)>> {
+    let mut window = Window {
+        start: 0,
+        buf: Vec::new(),
+    };
+    let mut members: Vec<(PathBuf, Member)> = Vec::new();
+    let mut by_name: HashMap<String, usize> = HashMap::new();
+    let mut long_name = None;
+    let mut pax = HashMap::new();
+    let mut pos = 0;
+    while pos + BLOCK <= size {
+        let header = window.get(backend, path, size, pos, BLOCK).await?;
+        // Two zero blocks end the archive.
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+        if !checksum_ok(&header) {
+            return Err(invalid_data(format!(
+                "Bad TAR header at {} of '{}'",
+                pos,
+                path.display()
+            )));
+        }
+        let kind = header[156];
+        let mut data_size = number(&header[124..136]);
+        if matches!(kind, 0 | b'0' | b'7' | b'5') {
+            if let Some(pax_size) = pax.get("size").and_then(|size: &String| size.parse().ok()) {
+                data_size = pax_size;
+            }
+        }
+        let data = pos + BLOCK;
+        pos = data + round_up(data_size);
+
+        match kind {
+            b'L' => {
+                let name = window.get(backend, path, size, data, data_size).await?;
+                long_name = Some(text(&name));
+                continue;
+            }
+            b'x' => {
+                let records = window.get(backend, path, size, data, data_size).await?;
+                pax = pax_records(&records);
+                continue;
+            }
+            _ => {}
+        }
+
+        let name = pax
+            .remove("path")
+            .or_else(|| long_name.take())
+            .unwrap_or_else(|| {
+                let name = text(&header[0..100]);
+                let prefix = text(&header[345..500]);
+                if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                    format!("{}/{}", prefix, name)
+                } else {
+                    name
+                }
+            });
+        let mtime = pax
+            .remove("mtime")
+            .and_then(|mtime| mtime.split('.').next()?.parse().ok())
+            .unwrap_or_else(|| number(&header[136..148]) as i64);
+        let mtime: Option<DateTime<Utc>> = Utc.timestamp_opt(mtime, 0).single();
+        pax.clear();
+        long_name = None;
+
+        let member = match kind {
+            0 | b'0' | b'7' => Member {
+                is_dir: name.ends_with('/'),
+                mtime,
+                size: data_size,
+                data: Data::Stored(data),
+            },
+            b'5' => Member {
+                is_dir: true,
+                mtime,
+                size: 0,
+                data: Data::Stored(data),
+            },
+            // Hard links share the data of an earlier member.
+            b'1' => {
+                let target = text(&header[157..257]);
+                match by_name.get(target.trim_start_matches("./")) {
+                    Some(&i) => Member {
+                        mtime,
+                        ..members[i].1.clone()
+                    },
+                    None => continue,
+                }
+            }
+            // Symlinks, devices and the like are left out.
+            _ => continue,
+        };
+        by_name.insert(name.trim_start_matches("./").to_string(), members.len());
+        members.push((PathBuf::from(name), member));
+    }
+    debug!("Found {} members in '{}'", members.len(), path.display());
+    Ok(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::tests::Bytes;
+
+    #[tokio::test]
+    async fn sample_members() {
+        let tar = include_bytes!("testdata/sample.tar");
+        let backend = Bytes::new("sample.tar", tar);
+        let members = members(&backend, Path::new("/sample.tar"), tar.len() as u64)
+            .await
+            .unwrap();
+        let long = format!("album/{}.txt", "long".repeat(30));
+        let summary: Vec<_> = members
+            .iter()
+            .map(|(path, m)| (path.to_str().unwrap(), m.is_dir, m.size))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("album/", true, 0),
+                ("album/01.txt", false, 6),
+                (long.as_str(), false, 14),
+                ("album/Björk – Jóga.txt", false, 9),
+                ("album/link.txt", false, 6),
+            ]
+        );
+        let mtime = Utc.timestamp_opt(1680423366, 0).single();
+        assert!(members.iter().all(|(_, m)| m.mtime == mtime));
+
+        let content = |i: usize| match members[i].1.data {
+            Data::Stored(start) => {
+                let start = start as usize;
+                &tar[start..start + members[i].1.size as usize]
+            }
+            data => panic!("Unexpected {:?}", data),
+        };
+        assert_eq!(content(1), b"hello\n");
+        assert_eq!(content(2), b"gnu long name\n");
+        assert_eq!(content(3), b"pax name\n");
+        assert_eq!(content(4), b"hello\n");
+    }
+}
@@ -0,0 +1,235 @@
+//! ZIP member table read from the central directory at the end of the file.
+
+use super::{read_at, Data, Member};
+use crate::client::ListingBackend;
+use crate::listing::invalid_data;
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use std::{
+    convert::TryInto,
+    io,
+    path::{Path, PathBuf},
+};
+
+const EOCD_SIGNATURE: u32 = 0x0605_4b50;
+const EOCD_SIZE: u64 = 22;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x0706_4b50;
+const ZIP64_LOCATOR_SIZE: u64 = 20;
+const ZIP64_EOCD_SIGNATURE: u32 = 0x0606_4b50;
+const ZIP64_EOCD_SIZE: usize = 56;
+const CENTRAL_SIGNATURE: u32 = 0x0201_4b50;
+const CENTRAL_SIZE: usize = 46;
+const LOCAL_SIGNATURE: u32 = 0x0403_4b50;
+pub const LOCAL_SIZE: usize = 30;
+/// Extra field carrying 64 bit sizes and offsets.
+const ZIP64_EXTRA: u16 = 0x0001;
+
+pub const STORED: u16 = 0;
+pub const DEFLATED: u16 = 8;
+
+fn u16_at(buf: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes(buf[pos..pos + 2].try_into().unwrap())
+}
+
+fn u32_at(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(buf[pos..pos + 8].try_into().unwrap())
+}
+
+/// MS-DOS date and time of a member, taken as UTC.
+fn dos_time(date: u16, time: u16) -> Option<DateTime<Utc>> {
+    let date = NaiveDate::from_ymd_opt(
+        1980 + (date >> 9) as i32,
+        ((date >> 5) & 0xf) as u32,
+        (date & 0x1f) as u32,
+    )?;
+    let time = date.and_hms_opt(
+        (time >> 11) as u32,
+        ((time >> 5) & 0x3f) as u32,
+        ((time & 0x1f) * 2) as u32,
+    )?;
+    Some(Utc.from_utc_datetime(&time))
+}
+
+/// Location and size of the central directory.
+async fn central_directory(
+    backend: &dyn ListingBackend,
+    path: &Path,
+    size: u64,
+) -> io::Result<(u64, u64)> {
+    if size < EOCD_SIZE {
+        return Err(invalid_data(format!(
+            "'{}' is not a ZIP archive",
+            path.display()
+        )));
+    }
+    // The end record sits in the last 64 KiB, behind an optional comment.
+    let tail_size = size.min(EOCD_SIZE + u16::MAX as u64);
+    let tail = read_at(backend, path, size - tail_size, tail_size as usize).await?;
+    let eocd = (0..=tail.len().saturating_sub(EOCD_SIZE as usize))
+        .rev()
+        .find(|&pos| u32_at(&tail, pos) == EOCD_SIGNATURE)
+        .ok_or_else(|| invalid_data(format!("'{}' is not a ZIP archive", path.display())))?;
+    let cd_size = u32_at(&tail, eocd + 12);
+    let cd_offset = u32_at(&tail, eocd + 16);
+    if cd_size != u32::MAX && cd_offset != u32::MAX {
+        return Ok((cd_offset as u64, cd_size as u64));
+    }
+
+    let eocd_offset = size - tail_size + eocd as u64;
+    if eocd_offset < ZIP64_LOCATOR_SIZE {
+        return Err(invalid_data("ZIP64 locator is missing"));
+    }
+    let locator = read_at(
+        backend,
+        path,
+        eocd_offset - ZIP64_LOCATOR_SIZE,
+        ZIP64_LOCATOR_SIZE as usize,
+    )
+    .await?;
+    if u32_at(&locator, 0) != ZIP64_LOCATOR_SIGNATURE {
+        return Err(invalid_data("ZIP64 locator is missing"));
+    }
+    let record = read_at(backend, path, u64_at(&locator, 8), ZIP64_EOCD_SIZE).await?;
+    if u32_at(&record, 0) != ZIP64_EOCD_SIGNATURE {
+        return Err(invalid_data("ZIP64 end of central directory is missing"));
+    }
+    Ok((u64_at(&record, 48), u64_at(&record, 40)))
+}
+
+/// Read members of the ZIP archive at `path` of `size` bytes.
+pub async fn members(
+    backend: &dyn ListingBackend,
+    path: &Path,
+    size: u64,
+) -> io::Result<Vec<(PathBuf, Member)>> {
+    let (cd_offset, cd_size) = central_directory(backend, path, size).await?;
+    let cd = read_at(backend, path, cd_offset, cd_size as usize).await?;
+
+    let mut members = Vec::new();
+    let mut pos = 0;
+    while pos + CENTRAL_SIZE <= cd.len() && u32_at(&cd, pos) == CENTRAL_SIGNATURE {
+        let flags = u16_at(&cd, pos + 8);
+        let method = u16_at(&cd, pos + 10);
+        let mtime = dos_time(u16_at(&cd, pos + 14), u16_at(&cd, pos + 12));
+        let mut compressed = u32_at(&cd, pos + 20) as u64;
+        let mut uncompressed = u32_at(&cd, pos + 24) as u64;
+        let name_len = u16_at(&cd, pos + 28) as usize;
+        let extra_len = u16_at(&cd, pos + 30) as usize;
+        let comment_len = u16_at(&cd, pos + 32) as usize;
+        let mut header = u32_at(&cd, pos + 42) as u64;
+
+        let name_start = pos + CENTRAL_SIZE;
+        let extra_start = name_start + name_len;
+        let next = extra_start + extra_len + comment_len;
+        if next > cd.len() {
+            return Err(invalid_data("Truncated ZIP central directory"));
+        }
+        let name = String::from_utf8_lossy(&cd[name_start..extra_start]).into_owned();
+
+        // 64 bit values follow in this order, only for fields set to all ones.
+        let mut extra = &cd[extra_start..extra_start + extra_len];
+        while extra.len() >= 4 {
+            let id = u16_at(extra, 0);
+            let len = (u16_at(extra, 2) as usize).min(extra.len() - 4);
+            if id == ZIP64_EXTRA {
+                let mut values = extra[4..4 + len].chunks_exact(8).map(|v| u64_at(v, 0));
+                for field in [&mut uncompressed, &mut compressed, &mut header] {
+                    if *field == u32::MAX as u64 {
+                        *field = values.next().unwrap_or(*field);
+                    }
+                }
+            }
+            extra = &extra[4 + len..];
+        }
+        pos = next;
+
+        if flags & 1 != 0 {
+            warn!(
+                "Skipping encrypted member '{}' of '{}'",
+                name,
+                path.display()
+            );
+            continue;
+        }
+        members.push((
+            PathBuf::from(&name),
+            Member {
+                is_dir: name.ends_with('/'),
+                mtime,
+                size: uncompressed,
+                data: Data::Zip {
+                    header,
+                    method,
+                    compressed,
+                },
+            },
+        ));
+    }
+    debug!("Found {} members in '{}'", members.len(), path.display());
+    Ok(members)
+}
+
+/// Offset of the data of a member whose local header is at `header`.
+pub async fn data_offset(
+    backend: &dyn ListingBackend,
+    path: &Path,
+    header: u64,
+) -> io::Result<u64> {
+    let local = read_at(backend, path, header, LOCAL_SIZE).await?;
+    if u32_at(&local, 0) != LOCAL_SIGNATURE {
+        return Err(invalid_data(format!(
+            "Bad ZIP local header at {} of '{}'",
+            header,
+            path.display()
+        )));
+    }
+    let name_len = u16_at(&local, 26) as u64;
+    let extra_len = u16_at(&local, 28) as u64;
+    Ok(header + LOCAL_SIZE as u64 + name_len + extra_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::tests::Bytes;
+
+    #[tokio::test]
+    async fn sample_members() {
+        let zip = include_bytes!("testdata/sample.zip");
+        let backend = Bytes::new("sample.zip", zip);
+        let members = members(&backend, Path::new("/sample.zip"), zip.len() as u64)
+            .await
+            .unwrap();
+        let mtime = Utc.with_ymd_and_hms(2023, 4, 2, 8, 16, 6).single();
+        let summary: Vec<_> = members
+            .iter()
+            .map(|(path, m)| (path.to_str().unwrap(), m.is_dir, m.size, m.mtime))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("album/", true, 0, mtime),
+                ("album/01.txt", false, 6, mtime),
+                ("album/notes.txt", false, 3000, mtime),
+            ]
+        );
+        match members[2].1.data {
+            Data::Zip { method, .. } => assert_eq!(method, DEFLATED),
+            data => panic!("Unexpected {:?}", data),
+        }
+    }
+
+    #[tokio::test]
+    async fn too_short() {
+        for data in [&b""[..], b"PK\x05"] {
+            let backend = Bytes::new("short.zip", data);
+            let err = members(&backend, Path::new("/short.zip"), data.len() as u64)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
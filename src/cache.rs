@@ -0,0 +1,384 @@
+//! Persistent block cache on the local disk, `cache.dir` in the config.
+//!
+//! Files are read from the server in aligned blocks kept as one file each,
+//! named after the server, path, ETag or mtime and block index. A changed
+//! file gets new names, the old blocks age out. The least recently used
+//! blocks are removed once the cache outgrows `cache.max_size`.
+
+use crate::client::{Changed, ListingBackend, RemoteEntry, Validators};
+use crate::local::blocking_error;
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use tokio::task;
+
+/// Size of the cached blocks, reads are widened to whole blocks.
+pub const BLOCK: u64 = 1024 * 1024;
+
+/// Fill the missing `blocks`, every run of them is fetched with a single
+/// `fetch(index, count)` call. A run fetched short means the file ended
+/// inside it, the blocks past the end are dropped.
+pub async fn fill_runs<T, F, R>(blocks: &mut Vec<Option<T>>, mut fetch: F) -> io::Result<()>
+where
+    F: FnMut(usize, usize) -> R,
+    R: Future<Output = io::Result<Vec<T>>>,
+{
+    let mut i = 0;
+    while i < blocks.len() {
+        if blocks[i].is_some() {
+            i += 1;
+            continue;
+        }
+        let run = blocks[i..].iter().take_while(|b| b.is_none()).count();
+        let fetched = fetch(i, run).await?;
+        let fetched_len = fetched.len();
+        for (j, block) in fetched.into_iter().enumerate() {
+            blocks[i + j] = Some(block);
+        }
+        if fetched_len < run {
+            blocks.truncate(i + fetched_len);
+            break;
+        }
+        i += run;
+    }
+    Ok(())
+}
+
+/// Blocks on disk ordered by last use.
+#[derive(Debug, Default)]
+struct Lru {
+    used: u64,
+    tick: u64,
+    /// Size and last use of every block by its file name.
+    blocks: HashMap<String, (u64, u64)>,
+    order: BTreeMap<u64, String>,
+}
+
+impl Lru {
+    fn touch(&mut self, name: &str) -> bool {
+        self.tick += 1;
+        match self.blocks.get_mut(name) {
+            Some((_, used)) => {
+                self.order.remove(used);
+                *used = self.tick;
+                self.order.insert(self.tick, name.to_string());
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, name: String, size: u64) {
+        if self.touch(&name) {
+            return;
+        }
+        self.used += size;
+        self.blocks.insert(name.clone(), (size, self.tick));
+        self.order.insert(self.tick, name);
+    }
+
+    /// Forget the least recently used block, returning its file name.
+    fn pop(&mut self) -> Option<String> {
+        let (_, name) = self.order.pop_first()?;
+        if let Some((size, _)) = self.blocks.remove(&name) {
+            self.used -= size;
+        }
+        Some(name)
+    }
+}
+
+/// Directory of cached blocks shared by all servers.
+#[derive(Debug)]
+pub struct BlockStore {
+    dir: PathBuf,
+    max_size: u64,
+    lru: Mutex<Lru>,
+}
+
+impl BlockStore {
+    /// Open the cache at `dir`, blocks left by earlier runs are kept and
+    /// ordered by the time they were written. Blocks half written when a run
+    /// was interrupted are removed.
+    pub fn open(dir: &Path, max_size: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut found = Vec::new();
+        for file in fs::read_dir(dir)? {
+            let file = file?;
+            let meta = file.metadata()?;
+            let name = file.file_name().to_string_lossy().into_owned();
+            if !meta.is_file() {
+                continue;
+            }
+            if name.ends_with(".tmp") {
+                debug!("Removing interrupted cache write {}", name);
+                if let Err(e) = fs::remove_file(file.path()) {
+                    warn!("Can't remove {}. {}", file.path().display(), e);
+                }
+                continue;
+            }
+            found.push((
+                meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                name,
+                meta.len(),
+            ));
+        }
+        found.sort();
+        let mut lru = Lru::default();
+        for (_, name, size) in found {
+            lru.insert(name, size);
+        }
+        info!(
+            "Caching up to {} bytes in {}, {} bytes cached",
+            max_size,
+            dir.display(),
+            lru.used
+        );
+        let store = Self {
+            dir: dir.to_path_buf(),
+            max_size,
+            lru: Mutex::new(lru),
+        };
+        store.evict();
+        Ok(store)
+    }
+
+    fn get(&self, name: &str) -> Option<Vec<u8>> {
+        if !self.lru.lock().unwrap().touch(name) {
+            return None;
+        }
+        match fs::read(self.dir.join(name)) {
+            Ok(data) => Some(data),
+            Err(e) => {
+                warn!("Can't read cached block {}. {}", name, e);
+                None
+            }
+        }
+    }
+
+    fn put(&self, name: String, data: &[u8]) {
+        // Written aside and renamed so a crash never leaves half a block.
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        let written = fs::write(&tmp, data).and_then(|_| fs::rename(&tmp, self.dir.join(&name)));
+        if let Err(e) = written {
+            warn!("Can't cache block {}. {}", name, e);
+            let _ = fs::remove_file(tmp);
+            return;
+        }
+        self.lru.lock().unwrap().insert(name, data.len() as u64);
+        self.evict();
+    }
+
+    fn evict(&self) {
+        let mut lru = self.lru.lock().unwrap();
+        while lru.used > self.max_size {
+            match lru.pop() {
+                Some(name) => {
+                    let _ = fs::remove_file(self.dir.join(name));
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// Server wrapper reading through a `BlockStore`.
+#[derive(Debug)]
+pub struct DiskCache {
+    inner: Arc<dyn ListingBackend>,
+    store: Arc<BlockStore>,
+    /// Tells apart the same path on different servers.
+    server: String,
+    /// ETag or mtime of every file seen by its path, `None` when the server
+    /// sends neither and the file is read past the cache.
+    versions: Mutex<HashMap<PathBuf, Option<String>>>,
+}
+
+/// What changes along with the content of a file.
+fn version(entry: &RemoteEntry) -> Option<String> {
+    entry.etag.clone().or_else(|| entry.mtime.clone())
+}
+
+impl DiskCache {
+    pub fn new(inner: Arc<dyn ListingBackend>, store: Arc<BlockStore>, server: &str) -> Self {
+        Self {
+            inner,
+            store,
+            server: server.to_string(),
+            versions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn remember(&self, path: PathBuf, entry: &RemoteEntry) {
+        if let Some(version) = version(entry) {
+            self.versions.lock().unwrap().insert(path, Some(version));
+        }
+    }
+
+    /// Remember versions of the files listed in the directory `path`.
+    fn remember_all(&self, path: &Path, entries: &[RemoteEntry]) {
+        for entry in entries {
+            if let (Some(name), Some("file")) = (&entry.name, entry.r#type.as_deref()) {
+                self.remember(path.join(name), entry);
+            }
+        }
+    }
+
+    /// Remember what the server told about the file `path` when asked
+    /// directly, including that it has no version.
+    fn remember_stat(&self, path: PathBuf, entry: &RemoteEntry) -> Option<String> {
+        let version = version(entry);
+        self.versions.lock().unwrap().insert(path, version.clone());
+        version
+    }
+
+    /// Version of the file at `path`, asking the server once when it wasn't
+    /// listed, e.g. when the tree came from the index manifest.
+    async fn version(&self, path: &Path) -> Option<String> {
+        if let Some(version) = self.versions.lock().unwrap().get(path) {
+            return version.clone();
+        }
+        let entry = self.inner.stat(path.to_path_buf()).await.ok()?;
+        self.remember_stat(path.to_path_buf(), &entry)
+    }
+
+    fn block_name(&self, path: &Path, version: &str, index: u64) -> String {
+        let key = format!("{}\0{}\0{}", self.server, path.display(), version);
+        format!("{}-{}", hex::encode(Sha256::digest(key.as_bytes())), index)
+    }
+
+    /// Blocks called `names` from the disk, `None` for misses.
+    async fn cached(&self, names: Vec<String>) -> io::Result<Vec<Option<Vec<u8>>>> {
+        let store = self.store.clone();
+        task::spawn_blocking(move || names.iter().map(|name| store.get(name)).collect())
+            .await
+            .map_err(blocking_error)
+    }
+
+    /// Fetch `count` blocks of `path` starting at block `first` with one
+    /// request and store them.
+    async fn fill(
+        &self,
+        path: &Path,
+        names: &[String],
+        first: u64,
+        count: u64,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        let data = self
+            .inner
+            .read(
+                path.to_path_buf(),
+                (count * BLOCK) as usize,
+                (first * BLOCK) as usize,
+            )
+            .await?;
+        let blocks: Vec<Vec<u8>> = data.chunks(BLOCK as usize).map(<[u8]>::to_vec).collect();
+        let store = self.store.clone();
+        let stored: Vec<(String, Vec<u8>)> =
+            names.iter().cloned().zip(blocks.iter().cloned()).collect();
+        task::spawn_blocking(move || {
+            for (name, block) in stored {
+                store.put(name, &block);
+            }
+        })
+        .await
+        .map_err(blocking_error)?;
+        Ok(blocks)
+    }
+}
+
+#[async_trait]
+impl ListingBackend for DiskCache {
+    async fn list(&self, path: PathBuf) -> io::Result<Vec<RemoteEntry>> {
+        let entries = self.inner.list(path.clone()).await?;
+        self.remember_all(&path, &entries);
+        Ok(entries)
+    }
+
+    async fn list_changed(&self, path: PathBuf, validators: &Validators) -> io::Result<Changed> {
+        let listing = self.inner.list_changed(path.clone(), validators).await?;
+        if let Some(listing) = &listing {
+            self.remember_all(&path, &listing.entries);
+        }
+        Ok(listing)
+    }
+
+    async fn read(&self, path: PathBuf, size: usize, offset: usize) -> io::Result<Vec<u8>> {
+        let version = match self.version(&path).await {
+            Some(version) if size > 0 => version,
+            _ => return self.inner.read(path, size, offset).await,
+        };
+        let (offset, end) = (offset as u64, (offset + size) as u64);
+        let (first, last) = (offset / BLOCK, (end - 1) / BLOCK);
+        let names: Vec<String> = (first..=last)
+            .map(|index| self.block_name(&path, &version, index))
+            .collect();
+        let mut blocks = self.cached(names.clone()).await?;
+
+        let (path, names) = (&path, &names);
+        fill_runs(&mut blocks, move |i, run| {
+            self.fill(path, &names[i..i + run], first + i as u64, run as u64)
+        })
+        .await?;
+
+        let mut data = Vec::with_capacity(blocks.len() * BLOCK as usize);
+        for block in blocks.into_iter().flatten() {
+            let full = block.len() as u64 == BLOCK;
+            data.extend_from_slice(&block);
+            if !full {
+                break;
+            }
+        }
+        let from = ((offset - first * BLOCK) as usize).min(data.len());
+        let to = (from + size).min(data.len());
+        Ok(data[from..to].to_vec())
+    }
+
+    async fn stat(&self, path: PathBuf) -> io::Result<RemoteEntry> {
+        let entry = self.inner.stat(path.clone()).await?;
+        self.remember_stat(path, &entry);
+        Ok(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_removes_interrupted_writes() {
+        let dir = std::env::temp_dir().join(format!("furumi-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("block-0"), b"done").unwrap();
+        fs::write(dir.join("block-1.tmp"), b"half").unwrap();
+
+        let store = BlockStore::open(&dir, 1024).unwrap();
+        assert_eq!(store.lru.lock().unwrap().used, 4);
+        assert_eq!(store.get("block-0").as_deref(), Some(&b"done"[..]));
+        assert!(!dir.join("block-1.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn runs_fetched_once() {
+        let mut blocks = vec![None, Some(1), None, None, Some(4), None, None];
+        let mut calls = Vec::new();
+        fill_runs(&mut blocks, |i, run| {
+            calls.push((i, run));
+            // The file ends after block 5.
+            let fetched = (i..(i + run).min(6)).collect();
+            async move { Ok(fetched) }
+        })
+        .await
+        .unwrap();
+        assert_eq!(calls, vec![(0, 1), (2, 2), (5, 2)]);
+        assert_eq!(blocks, (0..6).map(Some).collect::<Vec<_>>());
+    }
+}
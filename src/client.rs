@@ -1,90 +1,641 @@
 extern crate base64;
 
+use crate::archive::{ArchiveMode, Archives};
+use crate::cache::{BlockStore, DiskCache};
+use crate::config::Config;
+use crate::crypt::{Cipher, Crypt};
+use crate::listing::{self, ListingFormat};
+use crate::local::Local;
+use crate::mounts::MountTable;
+use crate::s3::S3;
+use crate::seekable::Seekable;
+use crate::union::Union;
+use async_trait::async_trait;
 use chrono::DateTime;
-use reqwest::{header, Client, Error};
-use serde::Deserialize;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use reqwest::{header, Client, Method, RequestBuilder, StatusCode, Url};
+use serde::{Deserialize, Serialize};
 use std::{
-    path::PathBuf,
-    time::SystemTime,
+    fmt::Debug,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
 
+/// Characters of a file name which can't appear in a URL path as is.
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Source of directory listings and file contents for `MemFS`.
+///
+/// The filesystem never talks to a server directly, so anything able to
+/// list a directory and serve byte ranges of a file can be mounted.
+#[async_trait]
+pub trait ListingBackend: Debug + Send + Sync {
+    /// List entries of the remote directory `path`.
+    async fn list(&self, path: PathBuf) -> io::Result<Vec<RemoteEntry>>;
+
+    /// List `path` again unless it didn't change since the listing
+    /// `validators` came with, `None` then. Backends unable to tell list it
+    /// every time.
+    async fn list_changed(&self, path: PathBuf, validators: &Validators) -> io::Result<Changed> {
+        let _ = validators;
+        Ok(Some(Listing::full(self.list(path).await?)))
+    }
+
+    /// Read `size` bytes of the remote file `path` starting at `offset`.
+    async fn read(&self, path: PathBuf, size: usize, offset: usize) -> io::Result<Vec<u8>>;
+
+    /// Fetch attributes of a single remote file.
+    async fn stat(&self, path: PathBuf) -> io::Result<RemoteEntry>;
+}
+
+/// What a server told about a listing to ask whether it changed later.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Directory listing along with what tells later whether it changed.
+#[derive(Default, Debug)]
+pub struct Listing {
+    pub entries: Vec<RemoteEntry>,
+    pub validators: Validators,
+    /// Some server behind the listing failed, entries missing from it may
+    /// still be there.
+    pub partial: bool,
+}
+
+impl Listing {
+    /// Complete listing of `entries` without validators.
+    pub fn full(entries: Vec<RemoteEntry>) -> Self {
+        Self {
+            entries,
+            ..Self::default()
+        }
+    }
+}
+
+/// Result of `ListingBackend::list_changed`, the new listing if there is one.
+pub type Changed = Option<Listing>;
+
+/// How long a failed mirror is skipped before it is tried again.
+const MIRROR_RETRY: Duration = Duration::from_secs(30);
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Default, Debug, Clone)]
 pub struct HTTP {
     client: Client,
+    mirrors: Arc<Vec<Mirror>>,
+    /// Remote directory shown as the mount root.
+    root: PathBuf,
+    /// `Auto` until the first listing probes the server.
+    format: Arc<Mutex<ListingFormat>>,
+}
+
+/// One of the servers carrying the same content.
+#[derive(Debug)]
+struct Mirror {
     server: String,
+    state: Mutex<MirrorState>,
+}
+
+#[derive(Default, Debug)]
+struct MirrorState {
+    /// Moving average of the response time.
+    latency: Option<Duration>,
+    /// The mirror is skipped until then after a failure.
+    down_until: Option<Instant>,
+}
+
+/// Response of one of the mirrors, read in full.
+struct Fetched {
+    url: String,
+    status: StatusCode,
+    headers: header::HeaderMap,
+    body: Vec<u8>,
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Deserialize)]
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct RemoteEntry {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
     pub r#type: Option<String>,
     pub mtime: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
 }
 
 impl RemoteEntry {
     pub fn parse_rfc2822(&self) -> SystemTime {
-        let rfc2822 = DateTime::parse_from_rfc2822(&self.mtime.as_ref().unwrap()).unwrap();
-        SystemTime::from(rfc2822)
+        self.mtime
+            .as_ref()
+            .and_then(|mtime| DateTime::parse_from_rfc2822(mtime).ok())
+            .map(SystemTime::from)
+            .unwrap_or(UNIX_EPOCH)
+    }
+}
+
+/// Backend of the whole mount as set up in `cfg`.
+pub fn backend(cfg: &Config) -> Arc<dyn ListingBackend> {
+    let mut backend = servers_backend(cfg);
+    if cfg.zstd {
+        backend = Arc::new(Seekable::new(backend));
+    }
+    match cfg.archives {
+        ArchiveMode::Off => backend,
+        mode => Arc::new(Archives::new(backend, mode)),
+    }
+}
+
+/// Backend of `cfg.mounts` or `cfg.servers`, merging the servers when
+/// there are several.
+fn servers_backend(cfg: &Config) -> Arc<dyn ListingBackend> {
+    // Every server is decrypted on its own so mount paths and merged names
+    // stay plain.
+    let cipher = cfg.crypt.as_ref().map(|crypt| Arc::new(Cipher::new(crypt)));
+    let store = cfg.cache.as_ref().and_then(|cache| {
+        match BlockStore::open(&cache.dir, cache.max_size) {
+            Ok(store) => Some(Arc::new(store)),
+            Err(e) => {
+                warn!("Can't use cache at {}, reading without it. {}", cache.dir.display(), e);
+                None
+            }
+        }
+    });
+    let serve = |server: &str, root: &Path| -> Arc<dyn ListingBackend> {
+        let mut backend = server_backend(server, root, cfg);
+        // Blocks are cached as the server has them, encrypted ones included.
+        if let Some(store) = &store {
+            let name = format!("{}{}", server, root.display());
+            backend = Arc::new(DiskCache::new(backend, store.clone(), &name));
+        }
+        match &cipher {
+            Some(cipher) => Arc::new(Crypt::new(backend, cipher.clone())),
+            None => backend,
+        }
+    };
+    if !cfg.mounts.is_empty() {
+        let mounts = cfg
+            .mounts
+            .iter()
+            .map(|mount| {
+                info!("Serving {} at {}", mount.server, mount.path);
+                let path = Path::new("/").join(&mount.path);
+                (path, serve(&mount.server, Path::new("/")))
+            })
+            .collect();
+        return Arc::new(MountTable::new(mounts));
+    }
+    match cfg.servers.as_slice() {
+        [server] => serve(server, &cfg.remote_root),
+        servers => Arc::new(Union::new(
+            servers
+                .iter()
+                .map(|server| serve(server, &cfg.remote_root))
+                .collect(),
+            cfg.conflict,
+        )),
+    }
+}
+
+/// Pick the backend serving `root` of `server` by its scheme. `webdav://`
+/// and `webdavs://` stand for WebDAV over HTTP and HTTPS.
+fn server_backend(server: &str, root: &Path, cfg: &Config) -> Arc<dyn ListingBackend> {
+    let below_root = || {
+        let root = root.to_string_lossy();
+        format!("{}/{}", server.trim_end_matches('/'), root.trim_start_matches('/'))
+    };
+    let webdav = server
+        .strip_prefix("webdav://")
+        .map(|rest| format!("http://{}", rest))
+        .or_else(|| server.strip_prefix("webdavs://").map(|rest| format!("https://{}", rest)));
+    if let Some(server) = webdav {
+        Arc::new(HTTP::new(
+            vec![server],
+            root.to_path_buf(),
+            cfg.username.clone(),
+            cfg.password.clone(),
+            ListingFormat::WebDav,
+        ))
+    } else if server.starts_with("s3://") {
+        Arc::new(S3::new(&below_root(), cfg.s3.clone()))
+    } else if server.starts_with("file://") {
+        Arc::new(Local::new(&below_root()))
+    } else {
+        let mut servers = vec![server.to_string()];
+        servers.extend(cfg.mirrors.iter().cloned());
+        Arc::new(HTTP::new(
+            servers,
+            root.to_path_buf(),
+            cfg.username.clone(),
+            cfg.password.clone(),
+            cfg.format,
+        ))
+    }
+}
+
+pub fn http_error(e: reqwest::Error) -> io::Error {
+    let kind = match e.status() {
+        Some(StatusCode::NOT_FOUND) => io::ErrorKind::NotFound,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("HTTP {:?}: {}", e.status(), e))
+}
+
+pub fn file_name(path: &Path) -> Option<String> {
+    path.file_name().map(|name| name.to_string_lossy().into_owned())
+}
+
+impl Mirror {
+    fn new(server: String) -> Self {
+        Self {
+            server,
+            state: Mutex::new(MirrorState::default()),
+        }
+    }
+
+    /// URL of the remote `path` on this mirror, each path segment percent-encoded.
+    fn url(&self, path: &Path) -> String {
+        let mut url = String::from(self.server.trim_end_matches('/'));
+        for segment in path.iter().filter(|segment| *segment != "/") {
+            url.push('/');
+            url.extend(utf8_percent_encode(&segment.to_string_lossy(), PATH_SEGMENT));
+        }
+        url
+    }
+
+    /// Sort key of the mirror, healthy and fast ones first. Mirrors never
+    /// measured come first so each gets a chance to show its latency.
+    fn rank(&self, now: Instant) -> (bool, Duration) {
+        let state = self.state.lock().unwrap();
+        let down = state.down_until.is_some_and(|until| until > now);
+        (down, state.latency.unwrap_or_default())
+    }
+
+    fn up(&self, elapsed: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.down_until = None;
+        state.latency = Some(match state.latency {
+            Some(latency) => (latency * 3 + elapsed) / 4,
+            None => elapsed,
+        });
+    }
+
+    fn down(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.down_until = Some(Instant::now() + MIRROR_RETRY);
     }
 }
 
 impl HTTP {
-    pub fn new(server: String, username: Option<String>, password: Option<String>) -> Self {
+    /// `servers` carry the same content, the first one is the primary and
+    /// the rest are its mirrors. The mount root maps to `root` on them.
+    pub fn new(
+        servers: Vec<String>,
+        root: PathBuf,
+        username: Option<String>,
+        password: Option<String>,
+        format: ListingFormat,
+    ) -> Self {
         let mut headers = header::HeaderMap::new();
-        match username {
-            Some(username) => {
-                info!("HTTP credentials has been configured. Securing connection.");
-                let mut _buf = String::new();
-                _buf.push_str(format!("{}:{}", username, password.as_ref().unwrap()).as_str());
-                let creds = base64::encode(_buf);
-
-                headers.insert(
-                    header::AUTHORIZATION,
-                    header::HeaderValue::from_str(format!("Basic {}", creds).as_str()).unwrap(),
-                );
-            }
-            None => {}
+        if let Some(username) = username {
+            info!("HTTP credentials has been configured. Securing connection.");
+            let mut _buf = String::new();
+            _buf.push_str(format!("{}:{}", username, password.as_ref().unwrap()).as_str());
+            let creds = base64::encode(_buf);
+
+            headers.insert(
+                header::AUTHORIZATION,
+                header::HeaderValue::from_str(format!("Basic {}", creds).as_str()).unwrap(),
+            );
         };
         let client = reqwest::Client::builder()
             .user_agent(APP_USER_AGENT)
             .default_headers(headers)
             .build()
             .unwrap();
-        Self { client, server }
-    }
-    pub async fn list(&self, path: PathBuf) -> Result<Vec<RemoteEntry>, Error> {
-        debug!("Fetching path '{}/{}'", self.server, path.display());
-        let client = &self.client;
-        let resp = client
-            .get(format!("{}/{}", self.server, path.display()).as_str())
-            .send()
-            .await?
-            .json::<Vec<RemoteEntry>>()
+        if servers.len() > 1 {
+            info!("Using {} mirrors of {}", servers.len() - 1, servers[0]);
+        }
+        Self {
+            client,
+            mirrors: Arc::new(servers.into_iter().map(Mirror::new).collect()),
+            root,
+            format: Arc::new(Mutex::new(format)),
+        }
+    }
+
+    /// Mirror indexes in the order they should be tried.
+    fn ranked(&self) -> Vec<usize> {
+        let now = Instant::now();
+        let mut order: Vec<usize> = (0..self.mirrors.len()).collect();
+        order.sort_by_key(|&i| self.mirrors[i].rank(now));
+        order
+    }
+
+    /// Send the request made by `build` for the remote `path` to the best
+    /// mirror, falling over to the next one on connection errors and server
+    /// side failures. Client errors like 404 are final.
+    async fn fetch<F>(&self, path: &Path, dir: bool, build: F) -> io::Result<Fetched>
+    where
+        F: Fn(&Client, &str) -> RequestBuilder + Sync,
+    {
+        let mut error = None;
+        for i in self.ranked() {
+            let mirror = &self.mirrors[i];
+            let mut url = mirror.url(&self.root.join(path.strip_prefix("/").unwrap_or(path)));
+            // Directory URLs end with a slash, WebDAV servers insist on it.
+            if dir {
+                url.push('/');
+            }
+            let started = Instant::now();
+            let result = async {
+                let resp = build(&self.client, &url).send().await?.error_for_status()?;
+                let status = resp.status();
+                let headers = resp.headers().clone();
+                let body = resp.bytes().await?.to_vec();
+                Ok::<_, reqwest::Error>((status, headers, body))
+            }
+            .await;
+            match result {
+                Ok((status, headers, body)) => {
+                    mirror.up(started.elapsed());
+                    return Ok(Fetched {
+                        url,
+                        status,
+                        headers,
+                        body,
+                    });
+                }
+                Err(e) if e.status().is_some_and(|status| status.is_client_error()) => {
+                    return Err(http_error(e));
+                }
+                Err(e) => {
+                    warn!("Mirror {} failed, trying next one. {}", mirror.server, e);
+                    mirror.down();
+                    error = Some(http_error(e));
+                }
+            }
+        }
+        Err(error.unwrap_or_else(|| io::Error::other("No servers configured")))
+    }
+
+    /// Listing format of the server, detected on the first call if needed.
+    async fn format(&self) -> io::Result<ListingFormat> {
+        let format = *self.format.lock().unwrap();
+        if format != ListingFormat::Auto {
+            return Ok(format);
+        }
+        let server = &self.mirrors[0].server;
+        let (format, settled) = self.detect_format().await.map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "Can't detect listing format of {}. Set `format` directive. {}",
+                    server, e
+                ),
+            )
+        })?;
+        if settled {
+            info!("Detected {:?} listing format of {}", format, server);
+            *self.format.lock().unwrap() = format;
+        } else {
+            debug!("Index of {} is empty, detecting its format again next time", server);
+        }
+        Ok(format)
+    }
+
+    /// Find out which listing format the server speaks by probing its root.
+    /// The format comes along with whether the probe settled it.
+    ///
+    /// WebDAV servers announce themselves with a `DAV` header on `OPTIONS`,
+    /// everything else is told apart by the index page itself.
+    async fn detect_format(&self) -> io::Result<(ListingFormat, bool)> {
+        let root = Path::new("/");
+        // Plenty of servers don't know `OPTIONS` and fail it with 501, the
+        // mirror is fine anyway.
+        let mirror = &self.mirrors[self.ranked()[0]];
+        let url = format!("{}/", mirror.url(&self.root));
+        if let Ok(resp) = self.client.request(Method::OPTIONS, &url).send().await {
+            if resp.status().is_success() && resp.headers().contains_key("DAV") {
+                debug!("'{}' announces DAV support", url);
+                return Ok((ListingFormat::WebDav, true));
+            }
+        }
+        let resp = self
+            .fetch(root, true, |client, url| {
+                client.get(url).header(
+                    header::ACCEPT,
+                    "application/json, application/xml;q=0.9, */*;q=0.8",
+                )
+            })
             .await?;
-        debug!("Found {} entries into '{}'", resp.len(), path.display());
-        Ok(resp)
+        let content_type = resp
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let body = String::from_utf8_lossy(&resp.body);
+        debug!("'{}' answers with {} index", resp.url, content_type);
+        let format = listing::detect(&content_type, &body).ok_or_else(|| {
+            listing::invalid_data(format!(
+                "Unrecognized index page of type '{}'",
+                content_type
+            ))
+        })?;
+        Ok((format, !listing::ambiguous(&body)))
     }
+}
 
-    pub async fn read(&self, path: PathBuf, size: usize, offset: usize) -> Result<Vec<u8>, Error> {
-        let mut headers = header::HeaderMap::new();
+#[async_trait]
+impl ListingBackend for HTTP {
+    async fn list(&self, path: PathBuf) -> io::Result<Vec<RemoteEntry>> {
+        let listing = self.list_changed(path, &Validators::default()).await?;
+        Ok(listing.map(|listing| listing.entries).unwrap_or_default())
+    }
+
+    async fn list_changed(&self, path: PathBuf, validators: &Validators) -> io::Result<Changed> {
+        debug!("Fetching path '{}'", path.display());
+        let format = self.format().await?;
+        // Only GET answers a matching `If-None-Match` with 304, a PROPFIND
+        // would fail with 412.
+        let conditional = format != ListingFormat::WebDav;
+        let resp = self
+            .fetch(&path, true, |client, url| {
+                let mut request = match format {
+                    ListingFormat::WebDav => client
+                        .request(Method::from_bytes(b"PROPFIND").unwrap(), url)
+                        .header("Depth", "1")
+                        .header(header::CONTENT_TYPE, "application/xml; charset=utf-8")
+                        .body(listing::webdav::PROPFIND),
+                    ListingFormat::Caddy => {
+                        client.get(url).header(header::ACCEPT, "application/json")
+                    }
+                    _ => client.get(url),
+                };
+                if !conditional {
+                    return request;
+                }
+                if let Some(etag) = &validators.etag {
+                    request = request.header(header::IF_NONE_MATCH, etag.as_str());
+                }
+                if let Some(last_modified) = &validators.last_modified {
+                    request = request.header(header::IF_MODIFIED_SINCE, last_modified.as_str());
+                }
+                request
+            })
+            .await?;
+        if resp.status == StatusCode::NOT_MODIFIED {
+            debug!("Listing of '{}' didn't change", path.display());
+            return Ok(None);
+        }
+        let value_of = |name| {
+            resp.headers
+                .get(name)
+                .and_then(|value: &header::HeaderValue| value.to_str().ok())
+                .map(String::from)
+        };
+        let validators = Validators {
+            etag: value_of(header::ETAG),
+            last_modified: value_of(header::LAST_MODIFIED),
+        };
+        let body = String::from_utf8_lossy(&resp.body);
+        let dir = Url::parse(&resp.url).map_err(listing::invalid_data)?;
+        let entries = listing::parse(format, &body, dir.path())?;
+        debug!("Found {} entries into '{}'", entries.len(), path.display());
+        Ok(Some(Listing {
+            entries,
+            validators,
+            partial: false,
+        }))
+    }
+
+    async fn read(&self, path: PathBuf, size: usize, offset: usize) -> io::Result<Vec<u8>> {
+        if size == 0 {
+            return Ok(Vec::new());
+        }
         let range = format!("bytes={}-{}", offset, {offset + size - 1});
         debug!("Reading path '{}' range {} ({} bytes)", path.display(), range, size);
-        headers.insert(
-            header::RANGE,
-            header::HeaderValue::from_str(range.as_str()).unwrap(),
-        );
-        let client = &self.client;
-        let resp = client
-            .get(format!("{}/{}", self.server, path.display()).as_str())
-            .headers(headers)
-            .send()
-            .await?
-            .bytes()
+        let resp = self
+            .fetch(&path, false, |client, url| {
+                client.get(url).header(header::RANGE, range.as_str())
+            })
+            .await?;
+        debug!("Received {} bytes of '{}'", resp.body.len(), resp.url);
+        if resp.status == StatusCode::PARTIAL_CONTENT {
+            Ok(resp.body)
+        } else {
+            // Servers without Range support send the whole file.
+            let start = offset.min(resp.body.len());
+            let end = (offset + size).min(resp.body.len());
+            Ok(resp.body[start..end].to_vec())
+        }
+    }
+
+    async fn stat(&self, path: PathBuf) -> io::Result<RemoteEntry> {
+        debug!("Stat path '{}'", path.display());
+        let resp = self
+            .fetch(&path, false, |client, url| client.head(url))
             .await?;
-        debug!("Received {} bytes of '{}'", resp.len(), path.display());
-        Ok(resp.to_vec())
+        let headers = &resp.headers;
+        let mtime = headers
+            .get(header::LAST_MODIFIED)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        let size = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+        let etag = headers
+            .get(header::ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        Ok(RemoteEntry {
+            name: file_name(&path),
+            r#type: Some(String::from("file")),
+            mtime,
+            size,
+            etag,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// Serve `answer` to every request, it gets the request method. Returns
+    /// the server URL.
+    async fn serve(answer: fn(&str) -> (&'static str, &'static str)) -> String {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let read = stream.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..read]);
+                let method = request.split(' ').next().unwrap_or_default();
+                let (status, body) = answer(method);
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn options_not_implemented() {
+        // python `http.server` and others fail `OPTIONS` with 501.
+        let url = serve(|method| match method {
+            "OPTIONS" => ("501 Not Implemented", ""),
+            _ => (
+                "200 OK",
+                r#"[{ "name":"01.flac", "type":"file", "mtime":"Sat, 01 Apr 2023 12:30:00 GMT", "size":42 }]"#,
+            ),
+        })
+        .await;
+        let servers = vec![url.clone(), url];
+        let http = HTTP::new(servers, PathBuf::from("/"), None, None, ListingFormat::Auto);
+        let entries = http.list(PathBuf::from("/")).await.unwrap();
+        assert_eq!(entries[0].name.as_deref(), Some("01.flac"));
+        assert_eq!(*http.format.lock().unwrap(), ListingFormat::Json);
+        for mirror in http.mirrors.iter() {
+            assert!(mirror.state.lock().unwrap().down_until.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_root_is_detected_again() {
+        let url = serve(|_| ("200 OK", "[]")).await;
+        let http = HTTP::new(vec![url], PathBuf::from("/"), None, None, ListingFormat::Auto);
+        assert!(http.list(PathBuf::from("/")).await.unwrap().is_empty());
+        assert_eq!(*http.format.lock().unwrap(), ListingFormat::Auto);
     }
 }
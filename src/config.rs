@@ -1,19 +1,102 @@
-use clap::{App, Arg};
-use std::process;
+use crate::archive::ArchiveMode;
+use crate::crypt::{NameEncoding, NameEncryption};
+use crate::listing::ListingFormat;
+use crate::union::ConflictPolicy;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use std::{
+    path::{Path, PathBuf},
+    process,
+};
 
 extern crate chrono;
 extern crate config;
 
 #[derive(Default, Debug, Clone, PartialEq)]
 pub struct Config {
-    pub server: String,
+    pub servers: Vec<String>,
+    pub conflict: ConflictPolicy,
+    pub mirrors: Vec<String>,
+    pub mounts: Vec<MountConfig>,
+    pub remote_root: PathBuf,
+    pub archives: ArchiveMode,
+    pub zstd: bool,
+    pub crypt: Option<CryptConfig>,
+    pub cache: Option<CacheConfig>,
+    /// Bytes of recently read blocks kept in memory.
+    pub memory_cache: u64,
+    /// Largest window read ahead of sequential reads, in bytes.
+    pub readahead: u64,
+    /// Fetch the start of the next file in the directory near the end of a
+    /// file.
+    pub prefetch_next: bool,
+    pub prefetch_size: u64,
     pub mountpoint: String,
     pub username: Option<String>,
     pub password: Option<String>,
+    pub format: ListingFormat,
+    pub s3: S3Config,
+    /// Seconds a directory listing is trusted before it is checked again.
+    pub listing_ttl: u64,
+    pub manifest: bool,
+    pub manifest_max_age: Option<u64>,
     pub conf_file: String,
 }
 
-pub fn read() -> Config {
+/// Item of the `mounts` table, `server` shown at `path` of the mount.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct MountConfig {
+    pub path: String,
+    pub server: String,
+}
+
+/// Settings of the `s3` section, used with `server: s3://bucket/prefix`.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub region: String,
+    pub access_key: Option<String>,
+    pub secret_key: Option<String>,
+}
+
+/// Settings of the `crypt` section, used when the servers hold a remote
+/// encrypted with rclone crypt.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct CryptConfig {
+    pub password: String,
+    /// rclone `password2`, the scrypt salt.
+    pub salt: Option<String>,
+    pub names: NameEncryption,
+    pub encoding: NameEncoding,
+    pub directory_names: bool,
+}
+
+/// Settings of the `cache` section, the on-disk block cache.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct CacheConfig {
+    pub dir: PathBuf,
+    /// Bytes kept on disk before the least recently used blocks go.
+    pub max_size: u64,
+}
+
+/// What furumi was asked to do on the command line.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Mount(Config),
+    Index(IndexArgs),
+}
+
+/// Arguments of `furumi index`.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct IndexArgs {
+    pub root: PathBuf,
+    pub listings: bool,
+    pub listing_name: String,
+    pub manifest: bool,
+    pub hash: bool,
+}
+
+pub fn read() -> Command {
     // Parse opts and args
     let cli_args = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
@@ -28,8 +111,43 @@ pub fn read() -> Config {
                 .takes_value(true)
                 .required(true),
         )
+        .subcommand(
+            SubCommand::with_name("index")
+                .about("Write static listings and index manifest of a local directory")
+                .arg(
+                    Arg::with_name("dir")
+                        .help("Directory to index")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("listings")
+                        .long("listings")
+                        .help("Write NGINX compatible JSON listing into every directory"),
+                )
+                .arg(
+                    Arg::with_name("listing-name")
+                        .long("listing-name")
+                        .help("File name of per directory listings")
+                        .default_value("index.html")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("no-manifest")
+                        .long("no-manifest")
+                        .help("Don't write the whole tree manifest"),
+                )
+                .arg(
+                    Arg::with_name("hash")
+                        .long("hash")
+                        .help("Put SHA-256 of every file into the manifest"),
+                ),
+        )
         .get_matches();
 
+    if let Some(index_args) = cli_args.subcommand_matches("index") {
+        return Command::Index(read_index_args(index_args));
+    }
+
     info!("Logger initialized. Set RUST_LOG=[debug,error,info,warn,trace] Default: info");
     info!(
         "Starting {} {}",
@@ -50,12 +168,26 @@ pub fn read() -> Config {
             process::exit(0x0001);
         }
     };
-    let server = match settings.get_str("server") {
-        Ok(server) => server,
-        Err(_) => {
-            error!("Server is not set in config. Set `server` directive.");
-            process::exit(0x0002);
+    // `server` is either a single server or a list of them to merge.
+    let servers: Vec<String> = match settings.get_array("server") {
+        Ok(servers) => servers
+            .into_iter()
+            .filter_map(|server| server.into_str().ok())
+            .collect(),
+        Err(_) => settings.get_str("server").into_iter().collect(),
+    };
+    let mounts = read_mounts(&settings);
+    if servers.is_empty() && mounts.is_empty() {
+        error!("Server is not set in config. Set `server` or `mounts` directive.");
+        process::exit(0x0002);
+    }
+    let servers = if mounts.is_empty() {
+        servers
+    } else {
+        if !servers.is_empty() {
+            warn!("Server can't be used along with mounts. Ignoring `server` directive.");
         }
+        Vec::new()
     };
     let mountpoint = match settings.get_str("mountpoint") {
         Ok(mountpoint) => mountpoint,
@@ -65,22 +197,258 @@ pub fn read() -> Config {
         }
     };
 
-    let username = match settings.get_str("username") {
-        Ok(username) => Some(username),
-        Err(_) => None,
-    };
-    let password = match settings.get_str("password") {
-        Ok(password) => Some(password),
-        Err(_) => None,
-    };
-    if password == None || username == None {
+    let mut mirrors: Vec<String> = settings
+        .get_array("mirrors")
+        .map(|mirrors| {
+            mirrors
+                .into_iter()
+                .filter_map(|mirror| mirror.into_str().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    if servers.len() != 1 && !mirrors.is_empty() {
+        warn!("Mirrors can be used with a single server only. Ignoring `mirrors` directive.");
+        mirrors.clear();
+    }
+
+    let remote_root = PathBuf::from("/").join(
+        settings
+            .get_str("remote_root")
+            .unwrap_or_default()
+            .trim_start_matches('/'),
+    );
+    if remote_root != Path::new("/") {
+        if servers.is_empty() {
+            warn!("Remote root can't be used along with mounts. Ignoring `remote_root` directive.");
+        } else {
+            info!("Mounting {} of the server", remote_root.display());
+        }
+    }
+
+    let username = settings.get_str("username").ok();
+    let password = settings.get_str("password").ok();
+    let s3 = read_s3(&settings);
+    let all_servers: Vec<&String> = servers
+        .iter()
+        .chain(mounts.iter().map(|mount| &mount.server))
+        .collect();
+    if all_servers.iter().any(|server| server.starts_with("s3://")) {
+        info!("Using S3 endpoint {} in region {}", s3.endpoint, s3.region);
+    }
+    if all_servers
+        .iter()
+        .any(|server| server.starts_with("http") || server.starts_with("webdav"))
+        && (password.is_none() || username.is_none())
+    {
         warn!("Insecure server detected. Set `username` and `password` directives to use auth.");
     }
-    Config {
-        server,
+    let format = match settings.get_str("format") {
+        Ok(format) => match format.parse() {
+            Ok(format) => format,
+            Err(e) => {
+                error!("{}. Set `format` directive to auto, json, xml, apache, webdav, caddy or html.", e);
+                process::exit(0x0006);
+            }
+        },
+        Err(_) => ListingFormat::default(),
+    };
+    info!("Using {:?} listing format", format);
+    let conflict = match settings.get_str("conflict") {
+        Ok(conflict) => match conflict.parse() {
+            Ok(conflict) => conflict,
+            Err(e) => {
+                error!("{}. Set `conflict` directive to first, newest or suffix.", e);
+                process::exit(0x0008);
+            }
+        },
+        Err(_) => ConflictPolicy::default(),
+    };
+    let archives = match settings.get_str("archives") {
+        Ok(archives) => match archives.parse() {
+            Ok(archives) => archives,
+            Err(e) => {
+                error!("{}. Set `archives` directive to off, replace or alongside.", e);
+                process::exit(0x0009);
+            }
+        },
+        Err(_) => ArchiveMode::default(),
+    };
+    let zstd = settings.get_bool("zstd").unwrap_or(false);
+    let crypt = read_crypt(&settings);
+    let cache = read_cache(&settings);
+    let memory_cache = read_size(&settings, "memory_cache", MEMORY_CACHE);
+    let readahead = read_size(&settings, "readahead", READAHEAD);
+    let prefetch_next = settings.get_bool("prefetch_next").unwrap_or(false);
+    let prefetch_size = read_size(&settings, "prefetch_size", PREFETCH_SIZE);
+    let listing_ttl = settings
+        .get_int("listing_ttl")
+        .map(|ttl| ttl.max(0) as u64)
+        .unwrap_or(LISTING_TTL);
+    let manifest = settings.get_bool("manifest").unwrap_or(true);
+    let manifest_max_age = settings
+        .get_int("manifest_max_age")
+        .ok()
+        .map(|max_age| max_age as u64);
+    Command::Mount(Config {
+        servers,
+        conflict,
+        mirrors,
+        mounts,
+        remote_root,
+        archives,
+        zstd,
+        crypt,
+        cache,
+        memory_cache,
+        readahead,
+        prefetch_next,
+        prefetch_size,
         username,
         password,
+        format,
+        s3,
+        listing_ttl,
+        manifest,
+        manifest_max_age,
         mountpoint,
         conf_file: config_file.to_string(),
+    })
+}
+
+fn read_index_args(args: &ArgMatches) -> IndexArgs {
+    IndexArgs {
+        root: PathBuf::from(args.value_of("dir").unwrap()),
+        listings: args.is_present("listings"),
+        listing_name: args.value_of("listing-name").unwrap().to_string(),
+        manifest: !args.is_present("no-manifest"),
+        hash: args.is_present("hash"),
+    }
+}
+
+fn read_mounts(settings: &config::Config) -> Vec<MountConfig> {
+    let mut mounts = Vec::new();
+    for mount in settings.get_array("mounts").unwrap_or_default() {
+        let mut mount = match mount.into_table() {
+            Ok(mount) => mount,
+            Err(e) => {
+                error!("{}. Items of `mounts` need `path` and `server`.", e);
+                process::exit(0x0002);
+            }
+        };
+        let mut field = |name: &str| mount.remove(name).and_then(|value| value.into_str().ok());
+        match (field("path"), field("server")) {
+            (Some(path), Some(server)) => mounts.push(MountConfig { path, server }),
+            _ => {
+                error!("Items of `mounts` need `path` and `server`.");
+                process::exit(0x0002);
+            }
+        }
+    }
+    mounts
+}
+
+fn read_crypt(settings: &config::Config) -> Option<CryptConfig> {
+    let password = settings.get_str("crypt.password").ok()?;
+    let names = match settings.get_str("crypt.filename_encryption") {
+        Ok(names) => match names.parse() {
+            Ok(names) => names,
+            Err(e) => {
+                error!("{}. Set `crypt.filename_encryption` directive to standard or off.", e);
+                process::exit(0x000A);
+            }
+        },
+        Err(_) => NameEncryption::default(),
+    };
+    let encoding = match settings.get_str("crypt.filename_encoding") {
+        Ok(encoding) => match encoding.parse() {
+            Ok(encoding) => encoding,
+            Err(e) => {
+                error!("{}. Set `crypt.filename_encoding` directive to base32 or base64.", e);
+                process::exit(0x000A);
+            }
+        },
+        Err(_) => NameEncoding::default(),
+    };
+    info!("Decrypting rclone crypt remote");
+    Some(CryptConfig {
+        password,
+        salt: settings.get_str("crypt.password2").ok(),
+        names,
+        encoding,
+        directory_names: settings
+            .get_bool("crypt.directory_name_encryption")
+            .unwrap_or(true),
+    })
+}
+
+/// Default `listing_ttl`, a minute.
+const LISTING_TTL: u64 = 60;
+/// Default `prefetch_size`, 1 MiB.
+const PREFETCH_SIZE: u64 = 1024 * 1024;
+/// Default `readahead`, 4 MiB.
+const READAHEAD: u64 = 4 * 1024 * 1024;
+/// Default `memory_cache`, 64 MiB.
+const MEMORY_CACHE: u64 = 64 * 1024 * 1024;
+/// Default `cache.max_size`, 1 GiB.
+const CACHE_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+fn read_cache(settings: &config::Config) -> Option<CacheConfig> {
+    let dir = settings.get_str("cache.dir").ok()?;
+    Some(CacheConfig {
+        dir: PathBuf::from(dir),
+        max_size: read_size(settings, "cache.max_size", CACHE_MAX_SIZE),
+    })
+}
+
+/// Size setting `key`, `default` when it is not set.
+fn read_size(settings: &config::Config, key: &str, default: u64) -> u64 {
+    match settings.get_str(key) {
+        Ok(size) => match parse_size(&size) {
+            Some(size) => size,
+            None => {
+                error!("Bad size '{}'. Set `{}` directive to bytes or a number with K, M or G suffix.", size, key);
+                process::exit(0x000B);
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+/// Size in bytes, optionally suffixed with K, M or G.
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let (number, unit) = match size.char_indices().find(|(_, c)| c.is_ascii_alphabetic()) {
+        Some((i, _)) => (&size[..i], &size[i..]),
+        None => (size, ""),
+    };
+    let multiplier = match unit.to_uppercase().trim_end_matches('B').trim_end_matches('I') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return None,
+    };
+    number.trim().parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+fn read_s3(settings: &config::Config) -> S3Config {
+    let region = settings
+        .get_str("s3.region")
+        .unwrap_or_else(|_| String::from("us-east-1"));
+    let endpoint = settings
+        .get_str("s3.endpoint")
+        .unwrap_or_else(|_| format!("https://s3.{}.amazonaws.com", region));
+    match reqwest::Url::parse(&endpoint) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+        _ => {
+            error!("Bad S3 endpoint '{}'. Set `s3.endpoint` directive to an http or https URL.", endpoint);
+            process::exit(0x000C);
+        }
+    }
+    S3Config {
+        endpoint,
+        region,
+        access_key: settings.get_str("s3.access_key").ok(),
+        secret_key: settings.get_str("s3.secret_key").ok(),
     }
 }
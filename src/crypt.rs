@@ -0,0 +1,646 @@
+//! Decrypting view of a remote encrypted with rclone crypt.
+//!
+//! Names are decrypted in listings and encrypted again on the way to the
+//! server, so paths need no bookkeeping. Contents are sealed in 64 KiB
+//! XSalsa20-Poly1305 chunks behind a header carrying the first nonce, reads
+//! fetch and open only the chunks covering the requested range.
+
+use crate::client::{file_name, Changed, ListingBackend, RemoteEntry, Validators};
+use crate::config::CryptConfig;
+use crate::listing::invalid_data;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes256;
+use async_trait::async_trait;
+use crypto_secretbox::{aead::Aead, XSalsa20Poly1305};
+use data_encoding::BASE32HEX_NOPAD;
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    io,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+const MAGIC: &[u8] = b"RCLONE\x00\x00";
+const NONCE_SIZE: usize = 24;
+const HEADER_SIZE: u64 = (MAGIC.len() + NONCE_SIZE) as u64;
+const BLOCK: u64 = 64 * 1024;
+const TAG_SIZE: u64 = 16;
+const SEALED_BLOCK: u64 = BLOCK + TAG_SIZE;
+/// Suffix of file names with `filename_encryption: off`.
+const PLAIN_SUFFIX: &str = ".bin";
+/// Salt used by rclone when `password2` is not set.
+const DEFAULT_SALT: [u8; 16] = [
+    0xa8, 0x0d, 0xf4, 0x3a, 0x8f, 0xbd, 0x03, 0x08, 0xa7, 0xca, 0xb8, 0x3e, 0x58, 0x1f, 0x86, 0xb1,
+];
+
+/// How file names are stored on the remote, rclone `filename_encryption`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NameEncryption {
+    /// Every name is encrypted with EME.
+    #[default]
+    Standard,
+    /// Names are kept, files get a `.bin` suffix.
+    Off,
+}
+
+impl FromStr for NameEncryption {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "standard" => Ok(NameEncryption::Standard),
+            "off" => Ok(NameEncryption::Off),
+            _ => Err(format!("Unsupported filename encryption '{}'", s)),
+        }
+    }
+}
+
+/// How encrypted names are written out, rclone `filename_encoding`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NameEncoding {
+    /// Lowercase base32hex without padding.
+    #[default]
+    Base32,
+    /// URL safe base64 without padding.
+    Base64,
+}
+
+impl FromStr for NameEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "base32" => Ok(NameEncoding::Base32),
+            "base64" => Ok(NameEncoding::Base64),
+            _ => Err(format!("Unsupported filename encoding '{}'", s)),
+        }
+    }
+}
+
+fn xor(block: &mut [u8], other: &[u8]) {
+    block.iter_mut().zip(other).for_each(|(a, b)| *a ^= b);
+}
+
+/// Multiply by x in GF(2^128), little endian as EME expects.
+fn double(block: &mut [u8; 16]) {
+    let carry = block[15] >> 7;
+    for i in (1..16).rev() {
+        block[i] = (block[i] << 1) | (block[i - 1] >> 7);
+    }
+    block[0] = (block[0] << 1) ^ (carry * 0x87);
+}
+
+/// Keys derived from the passwords along with the settings of the remote.
+pub struct Cipher {
+    names: NameEncryption,
+    encoding: NameEncoding,
+    directory_names: bool,
+    name_key: Aes256,
+    name_tweak: [u8; 16],
+    data: XSalsa20Poly1305,
+}
+
+impl std::fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Cipher")
+            .field("names", &self.names)
+            .field("encoding", &self.encoding)
+            .field("directory_names", &self.directory_names)
+            .finish()
+    }
+}
+
+impl Cipher {
+    pub fn new(cfg: &CryptConfig) -> Self {
+        // 32 bytes of data key, 32 of name key and 16 of name tweak. Like
+        // rclone an empty password leaves them all zero.
+        let mut key = [0; 80];
+        if !cfg.password.is_empty() {
+            let salt = match &cfg.salt {
+                Some(salt) if !salt.is_empty() => salt.as_bytes(),
+                _ => &DEFAULT_SALT,
+            };
+            // The length in the parameters is only used for password hashes.
+            let params = scrypt::Params::new(14, 8, 1, scrypt::Params::RECOMMENDED_LEN).unwrap();
+            scrypt::scrypt(cfg.password.as_bytes(), salt, &params, &mut key).unwrap();
+        }
+        let data_key: [u8; 32] = key[..32].try_into().unwrap();
+        let name_key: [u8; 32] = key[32..64].try_into().unwrap();
+        Self {
+            names: cfg.names,
+            encoding: cfg.encoding,
+            directory_names: cfg.directory_names,
+            name_key: Aes256::new(&name_key.into()),
+            name_tweak: key[64..80].try_into().unwrap(),
+            data: XSalsa20Poly1305::new(&data_key.into()),
+        }
+    }
+
+    /// EME wide block transform of `input`, a whole number of AES blocks.
+    fn eme(&self, input: &[u8], encrypt: bool) -> Vec<u8> {
+        let aes = |block: &mut [u8]| {
+            let aes_block: [u8; 16] = (&*block).try_into().unwrap();
+            let mut aes_block = aes_block.into();
+            if encrypt {
+                self.name_key.encrypt_block(&mut aes_block)
+            } else {
+                self.name_key.decrypt_block(&mut aes_block)
+            }
+            block.copy_from_slice(&aes_block);
+        };
+        let mut mask = [0; 16].into();
+        self.name_key.encrypt_block(&mut mask);
+        let mut mask: [u8; 16] = mask.into();
+        let masks: Vec<[u8; 16]> = (0..input.len() / 16)
+            .map(|_| {
+                double(&mut mask);
+                mask
+            })
+            .collect();
+
+        let mut output = input.to_vec();
+        for (block, mask) in output.chunks_exact_mut(16).zip(&masks) {
+            xor(block, mask);
+            aes(block);
+        }
+        let mut mp = self.name_tweak;
+        output
+            .chunks_exact(16)
+            .for_each(|block| xor(&mut mp, block));
+        let mut mc = mp;
+        aes(&mut mc);
+        let mut m = mp;
+        xor(&mut m, &mc);
+        for block in output.chunks_exact_mut(16).skip(1) {
+            double(&mut m);
+            xor(block, &m);
+        }
+        let mut first = mc;
+        xor(&mut first, &self.name_tweak);
+        output
+            .chunks_exact(16)
+            .skip(1)
+            .for_each(|block| xor(&mut first, block));
+        output[..16].copy_from_slice(&first);
+        for (block, mask) in output.chunks_exact_mut(16).zip(&masks) {
+            aes(block);
+            xor(block, mask);
+        }
+        output
+    }
+
+    fn encrypt_segment(&self, name: &str) -> String {
+        let pad = 16 - name.len() % 16;
+        let mut padded = name.as_bytes().to_vec();
+        padded.resize(name.len() + pad, pad as u8);
+        let sealed = self.eme(&padded, true);
+        match self.encoding {
+            NameEncoding::Base32 => BASE32HEX_NOPAD.encode(&sealed).to_lowercase(),
+            NameEncoding::Base64 => base64::encode_config(&sealed, base64::URL_SAFE_NO_PAD),
+        }
+    }
+
+    fn decrypt_segment(&self, name: &str) -> Option<String> {
+        let sealed = match self.encoding {
+            NameEncoding::Base32 => BASE32HEX_NOPAD
+                .decode(name.to_uppercase().as_bytes())
+                .ok()?,
+            NameEncoding::Base64 => base64::decode_config(name, base64::URL_SAFE_NO_PAD).ok()?,
+        };
+        if sealed.is_empty() || sealed.len() % 16 != 0 || sealed.len() > 16 * 128 {
+            return None;
+        }
+        let mut padded = self.eme(&sealed, false);
+        let pad = *padded.last()? as usize;
+        if pad == 0
+            || pad > 16
+            || padded[padded.len() - pad..]
+                .iter()
+                .any(|b| *b as usize != pad)
+        {
+            return None;
+        }
+        padded.truncate(padded.len() - pad);
+        String::from_utf8(padded).ok()
+    }
+
+    /// Name on the remote of the file or directory `name`.
+    fn encrypt_name(&self, name: &str, is_dir: bool) -> String {
+        match self.names {
+            NameEncryption::Off if is_dir => name.to_string(),
+            NameEncryption::Off => format!("{}{}", name, PLAIN_SUFFIX),
+            NameEncryption::Standard if is_dir && !self.directory_names => name.to_string(),
+            NameEncryption::Standard => self.encrypt_segment(name),
+        }
+    }
+
+    /// Plain name of the remote file or directory `name`, `None` for
+    /// names which were not written by this remote.
+    fn decrypt_name(&self, name: &str, is_dir: bool) -> Option<String> {
+        match self.names {
+            NameEncryption::Off if is_dir => Some(name.to_string()),
+            NameEncryption::Off => name.strip_suffix(PLAIN_SUFFIX).map(str::to_string),
+            NameEncryption::Standard if is_dir && !self.directory_names => Some(name.to_string()),
+            NameEncryption::Standard => self.decrypt_segment(name),
+        }
+    }
+
+    /// Remote path of the file or directory at mount path `path`.
+    fn remote(&self, path: &Path, is_dir: bool) -> PathBuf {
+        let segments: Vec<&str> = path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => name.to_str(),
+                _ => None,
+            })
+            .collect();
+        let mut remote = PathBuf::from("/");
+        for (i, name) in segments.iter().enumerate() {
+            let last = i + 1 == segments.len();
+            remote.push(self.encrypt_name(name, is_dir || !last));
+        }
+        remote
+    }
+}
+
+/// Size of the content of an encrypted file of `size` bytes.
+fn decrypted_size(size: u64) -> Option<u64> {
+    let body = size.checked_sub(HEADER_SIZE)?;
+    let (blocks, rest) = (body / SEALED_BLOCK, body % SEALED_BLOCK);
+    match rest {
+        0 => Some(blocks * BLOCK),
+        rest if rest > TAG_SIZE => Some(blocks * BLOCK + rest - TAG_SIZE),
+        _ => None,
+    }
+}
+
+/// Nonce of chunk `index`, the header nonce plus the index as a little
+/// endian number.
+fn chunk_nonce(nonce: &[u8; NONCE_SIZE], index: u64) -> [u8; NONCE_SIZE] {
+    let mut nonce = *nonce;
+    let (mut add, mut carry) = (index, 0u16);
+    for byte in nonce.iter_mut() {
+        carry += *byte as u16 + (add & 0xff) as u16;
+        *byte = carry as u8;
+        carry >>= 8;
+        add >>= 8;
+    }
+    nonce
+}
+
+/// Size, ETag and mtime of a file as listed, they change along with its
+/// header nonce when it is replaced.
+type Version = (Option<u64>, Option<String>, Option<String>);
+
+#[derive(Debug)]
+pub struct Crypt {
+    inner: Arc<dyn ListingBackend>,
+    cipher: Arc<Cipher>,
+    /// Header nonces by the remote path of the file.
+    nonces: Mutex<HashMap<PathBuf, [u8; NONCE_SIZE]>>,
+    /// Last seen version of every file by its remote path.
+    versions: Mutex<HashMap<PathBuf, Version>>,
+}
+
+impl Crypt {
+    pub fn new(inner: Arc<dyn ListingBackend>, cipher: Arc<Cipher>) -> Self {
+        Self {
+            inner,
+            cipher,
+            nonces: Mutex::new(HashMap::new()),
+            versions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Remember the version of the file at `remote` told by the server,
+    /// forgetting its nonce when the file changed since last time.
+    fn remember(&self, remote: PathBuf, entry: &RemoteEntry) {
+        let version = (entry.size, entry.etag.clone(), entry.mtime.clone());
+        let mut versions = self.versions.lock().unwrap();
+        if versions.get(&remote).is_some_and(|old| *old != version) {
+            debug!("'{}' changed, reading its nonce again", remote.display());
+            self.nonces.lock().unwrap().remove(&remote);
+        }
+        versions.insert(remote, version);
+    }
+
+    /// Nonce of the first chunk of the file at `remote`, read on first use.
+    async fn nonce(&self, remote: &Path) -> io::Result<[u8; NONCE_SIZE]> {
+        if let Some(nonce) = self.nonces.lock().unwrap().get(remote) {
+            return Ok(*nonce);
+        }
+        let header = self
+            .inner
+            .read(remote.to_path_buf(), HEADER_SIZE as usize, 0)
+            .await?;
+        if header.len() < HEADER_SIZE as usize || !header.starts_with(MAGIC) {
+            return Err(invalid_data(format!(
+                "'{}' is not encrypted by rclone",
+                remote.display()
+            )));
+        }
+        let nonce: [u8; NONCE_SIZE] = header[MAGIC.len()..].try_into().unwrap();
+        self.nonces
+            .lock()
+            .unwrap()
+            .insert(remote.to_path_buf(), nonce);
+        Ok(nonce)
+    }
+
+    /// Plain listing of the remote directory `remote` listing `entries`.
+    fn decrypt_entries(&self, remote: &Path, entries: Vec<RemoteEntry>) -> Vec<RemoteEntry> {
+        for entry in &entries {
+            if let (Some(name), Some("file")) = (&entry.name, entry.r#type.as_deref()) {
+                self.remember(remote.join(name), entry);
+            }
+        }
+        entries
+            .into_iter()
+            .filter_map(|entry| self.decrypt_entry(entry))
+            .collect()
+    }
+
+    /// Listing entry of the remote `entry` with its plain name and size.
+    fn decrypt_entry(&self, mut entry: RemoteEntry) -> Option<RemoteEntry> {
+        let is_dir = entry.r#type.as_deref() == Some("directory");
+        let name = entry.name.take()?;
+        entry.name = match self.cipher.decrypt_name(&name, is_dir) {
+            Some(plain) => Some(plain),
+            None => {
+                debug!("Skipping '{}', its name can't be decrypted", name);
+                return None;
+            }
+        };
+        if !is_dir {
+            entry.size = match entry.size.map(decrypted_size) {
+                Some(None) => {
+                    warn!("Skipping '{}', it is too short to be encrypted", name);
+                    return None;
+                }
+                size => size.flatten(),
+            };
+        }
+        Some(entry)
+    }
+}
+
+#[async_trait]
+impl ListingBackend for Crypt {
+    async fn list(&self, path: PathBuf) -> io::Result<Vec<RemoteEntry>> {
+        let remote = self.cipher.remote(&path, true);
+        let entries = self.inner.list(remote.clone()).await?;
+        Ok(self.decrypt_entries(&remote, entries))
+    }
+
+    async fn list_changed(&self, path: PathBuf, validators: &Validators) -> io::Result<Changed> {
+        let remote = self.cipher.remote(&path, true);
+        let listing = self.inner.list_changed(remote.clone(), validators).await?;
+        Ok(listing.map(|mut listing| {
+            listing.entries = self.decrypt_entries(&remote, listing.entries);
+            listing
+        }))
+    }
+
+    async fn read(&self, path: PathBuf, size: usize, offset: usize) -> io::Result<Vec<u8>> {
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        let remote = self.cipher.remote(&path, false);
+        let nonce = self.nonce(&remote).await?;
+        let (offset, end) = (offset as u64, (offset + size) as u64);
+        let (first, last) = (offset / BLOCK, (end - 1) / BLOCK);
+        let sealed = self
+            .inner
+            .read(
+                remote.clone(),
+                ((last - first + 1) * SEALED_BLOCK) as usize,
+                (HEADER_SIZE + first * SEALED_BLOCK) as usize,
+            )
+            .await?;
+        let mut content = Vec::with_capacity(sealed.len());
+        for (i, chunk) in sealed.chunks(SEALED_BLOCK as usize).enumerate() {
+            let index = first + i as u64;
+            let nonce = chunk_nonce(&nonce, index);
+            let plain = self
+                .cipher
+                .data
+                .decrypt(&nonce.into(), chunk)
+                .map_err(|_| {
+                    // The file may have been replaced along with its nonce.
+                    self.nonces.lock().unwrap().remove(&remote);
+                    invalid_data(format!(
+                        "Can't decrypt block {} of '{}'",
+                        index,
+                        path.display()
+                    ))
+                })?;
+            content.extend_from_slice(&plain);
+        }
+        let from = ((offset - first * BLOCK) as usize).min(content.len());
+        let to = (from + size).min(content.len());
+        Ok(content[from..to].to_vec())
+    }
+
+    async fn stat(&self, path: PathBuf) -> io::Result<RemoteEntry> {
+        let remote = self.cipher.remote(&path, false);
+        let mut entry = self.inner.stat(remote.clone()).await?;
+        self.remember(remote.clone(), &entry);
+        entry.name = file_name(&path);
+        entry.size = match entry.size {
+            Some(size) => Some(decrypted_size(size).ok_or_else(|| {
+                invalid_data(format!(
+                    "'{}' is too short to be encrypted",
+                    remote.display()
+                ))
+            })?),
+            None => None,
+        };
+        Ok(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::tests::Bytes;
+    use crate::filesystem::tests::Server;
+
+    // Known answers below come from rclone's `backend/crypt/cipher_test.go`.
+    // Like there, the keys are all zero unless a password is set.
+
+    fn cipher(encoding: NameEncoding) -> Cipher {
+        Cipher::new(&CryptConfig {
+            encoding,
+            directory_names: true,
+            ..CryptConfig::default()
+        })
+    }
+
+    #[test]
+    fn base32_names() {
+        let cipher = cipher(NameEncoding::Base32);
+        for (plain, sealed) in [
+            ("1", "p0e52nreeaj0a5ea7s64m4j72s"),
+            ("12", "l42g6771hnv3an9cgc8cr2n1ng"),
+            ("123", "qgm4avr35m5loi1th53ato71v0"),
+            ("1234", "8ivr2e9plj3c3esisjpdisikos"),
+            ("12345", "rh9vu63q3o29eqmj4bg6gg7s44"),
+            ("123456", "bn717l3alepn75b2fb2ejmi4b4"),
+            ("1234567", "n6bo9jmb1qe3b1ogtj5qkf19k8"),
+            ("12345678", "u9t24j7uaq94dh5q53m3s4t9ok"),
+            ("123456789", "37hn305g6j12d1g0kkrl7ekbs4"),
+            ("1234567890", "ot8d91eplaglb62k2b1trm2qv0"),
+            ("12345678901", "h168vvrgb53qnrtvvmb378qrcs"),
+            ("123456789012", "s3hsdf9e29ithrqbjqu01t8q2s"),
+            ("1234567890123", "cf3jimlv1q2oc553mv7s3mh3eo"),
+            ("12345678901234", "moq0uqdlqrblrc5pa5u5c7hq9g"),
+        ] {
+            assert_eq!(cipher.encrypt_segment(plain), sealed);
+            assert_eq!(cipher.decrypt_segment(sealed).as_deref(), Some(plain));
+        }
+    }
+
+    #[test]
+    fn base64_names() {
+        let cipher = cipher(NameEncoding::Base64);
+        for (plain, sealed) in [
+            ("12", "qQUDHOGN_jVdLIMQzYrhvA"),
+            ("123", "1CxFf2Mti1xIPYlGruDh-A"),
+            ("1234", "RL-xOTmsxsG7kuTy2XJUxw"),
+            ("12345", "3FP_GHoeBJdq0yLgaED8IQ"),
+            ("123456", "Xc4T1Gqrs3OVYnrE6dpEWQ"),
+            ("1234567", "uZeEzssOnDWHEOzLqjwpog"),
+        ] {
+            assert_eq!(cipher.encrypt_segment(plain), sealed);
+            assert_eq!(cipher.decrypt_segment(sealed).as_deref(), Some(plain));
+        }
+    }
+
+    #[test]
+    fn long_names() {
+        let cipher = cipher(NameEncoding::Base32);
+        let name = "01 - A name long enough to take several AES blocks.flac";
+        let sealed = cipher.encrypt_segment(name);
+        assert_eq!(cipher.decrypt_segment(&sealed).as_deref(), Some(name));
+        assert_eq!(cipher.decrypt_segment("not-a-sealed-name"), None);
+    }
+
+    #[test]
+    fn paths() {
+        let cipher = cipher(NameEncoding::Base32);
+        assert_eq!(
+            cipher.remote(Path::new("/1/12/123"), false),
+            Path::new(
+                "/p0e52nreeaj0a5ea7s64m4j72s/l42g6771hnv3an9cgc8cr2n1ng/qgm4avr35m5loi1th53ato71v0"
+            )
+        );
+    }
+
+    #[test]
+    fn key_from_password() {
+        let cipher = Cipher::new(&CryptConfig {
+            password: String::from("potato"),
+            ..CryptConfig::default()
+        });
+        let key = [
+            0x74, 0x55, 0xc7, 0x1a, 0xb1, 0x7c, 0x86, 0x5b, 0x84, 0x71, 0xf4, 0x7b, 0x79, 0xac,
+            0xb0, 0x7e, 0xb3, 0x1d, 0x56, 0x78, 0xb8, 0x0c, 0x7e, 0x2e, 0xaf, 0x4f, 0xc8, 0x06,
+            0x6a, 0x9e, 0xe4, 0x68,
+        ];
+        let nonce = [0; NONCE_SIZE];
+        let sealed = XSalsa20Poly1305::new(&key.into())
+            .encrypt(&nonce.into(), &b"potato"[..])
+            .unwrap();
+        assert_eq!(
+            cipher.data.decrypt(&nonce.into(), &sealed[..]).unwrap(),
+            b"potato"
+        );
+    }
+
+    #[test]
+    fn sizes() {
+        let chunk = SEALED_BLOCK;
+        assert_eq!(decrypted_size(0), None);
+        assert_eq!(decrypted_size(HEADER_SIZE - 1), None);
+        assert_eq!(decrypted_size(HEADER_SIZE), Some(0));
+        assert_eq!(decrypted_size(HEADER_SIZE + 1), None);
+        assert_eq!(decrypted_size(HEADER_SIZE + TAG_SIZE), None);
+        assert_eq!(decrypted_size(HEADER_SIZE + TAG_SIZE + 1), Some(1));
+        assert_eq!(decrypted_size(HEADER_SIZE + chunk), Some(BLOCK));
+        assert_eq!(
+            decrypted_size(HEADER_SIZE + chunk + TAG_SIZE + 1),
+            Some(BLOCK + 1)
+        );
+        assert_eq!(decrypted_size(HEADER_SIZE + 3 * chunk), Some(3 * BLOCK));
+    }
+
+    #[test]
+    fn nonces() {
+        let mut all_ones = [0xff; NONCE_SIZE];
+        assert_eq!(chunk_nonce(&all_ones, 1), [0; NONCE_SIZE]);
+        all_ones[NONCE_SIZE - 1] = 0;
+        let mut carried = [0; NONCE_SIZE];
+        carried[NONCE_SIZE - 1] = 1;
+        assert_eq!(chunk_nonce(&all_ones, 1), carried);
+
+        let zero = [0; NONCE_SIZE];
+        let mut added = [0; NONCE_SIZE];
+        added[..8].copy_from_slice(&[0xf0, 0xde, 0xbc, 0x9a, 0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(chunk_nonce(&zero, 0x1234_5678_9abc_def0), added);
+        let mut byte = [0; NONCE_SIZE];
+        byte[0] = 0xff;
+        let mut next = [0; NONCE_SIZE];
+        next[1] = 1;
+        assert_eq!(chunk_nonce(&byte, 1), next);
+        assert_eq!(chunk_nonce(&byte, 0), byte);
+    }
+
+    /// `file1` of rclone: a header with the nonce 1..=24 and the single
+    /// byte 1 sealed with the zero key.
+    #[tokio::test]
+    async fn small_file() {
+        let mut file = MAGIC.to_vec();
+        file.extend(1..=NONCE_SIZE as u8);
+        file.extend([
+            0x09, 0x5b, 0x44, 0x6c, 0xd6, 0x23, 0x7b, 0xbc, 0xb0, 0x8d, 0x09, 0xfb, 0x52, 0x4c,
+            0xe5, 0x65, 0xaa,
+        ]);
+        let crypt = Crypt::new(
+            Arc::new(Bytes::new("p0e52nreeaj0a5ea7s64m4j72s", &file)),
+            Arc::new(cipher(NameEncoding::Base32)),
+        );
+        let entries = crypt.list(PathBuf::from("/")).await.unwrap();
+        assert_eq!(entries[0].name.as_deref(), Some("1"));
+        assert_eq!(entries[0].size, Some(1));
+        assert_eq!(crypt.read(PathBuf::from("/1"), 10, 0).await.unwrap(), [1]);
+    }
+
+    #[tokio::test]
+    async fn replaced_file() {
+        let cipher = Arc::new(cipher(NameEncoding::Base32));
+        let sealed = |nonce: [u8; NONCE_SIZE], plain: &[u8]| {
+            let mut file = MAGIC.to_vec();
+            file.extend(nonce);
+            file.extend(cipher.data.encrypt(&nonce.into(), plain).unwrap());
+            file
+        };
+        let server = Arc::new(Server::new(&[(
+            "p0e52nreeaj0a5ea7s64m4j72s",
+            &sealed([1; NONCE_SIZE], b"a"),
+        )]));
+        let crypt = Crypt::new(server.clone(), cipher.clone());
+        crypt.list(PathBuf::from("/")).await.unwrap();
+        assert_eq!(crypt.read(PathBuf::from("/1"), 10, 0).await.unwrap(), b"a");
+
+        // Replaced along with its nonce, the new one is read after listing.
+        server.put(
+            "p0e52nreeaj0a5ea7s64m4j72s",
+            &sealed([2; NONCE_SIZE], b"bc"),
+        );
+        let entries = crypt.list(PathBuf::from("/")).await.unwrap();
+        assert_eq!(entries[0].size, Some(2));
+        assert_eq!(crypt.read(PathBuf::from("/1"), 10, 0).await.unwrap(), b"bc");
+    }
+}
@@ -1,30 +1,32 @@
 #![allow(clippy::unnecessary_mut_passed)]
 #![deny(clippy::unimplemented)]
 
+use crate::cache::fill_runs;
 use crate::config;
-use crate::client;
+use crate::client::{self, ListingBackend, RemoteEntry, Validators};
+use crate::manifest::Manifest;
+use crate::memcache::{self, MemCache};
 
 use polyfuse::{
     io::{Reader, Writer},
     op,
-    reply::{Reply, ReplyAttr, ReplyEntry, ReplyOpen},
+    reply::{Reply, ReplyAttr, ReplyEntry, ReplyOpen, ReplyXattr},
     Context, DirEntry, FileAttr, Filesystem, Operation,
 };
 use slab::Slab;
 
-use crate::client::HTTP;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::{
     collections::hash_map::{Entry, HashMap},
     ffi::{OsStr, OsString},
+    os::unix::ffi::OsStrExt,
     fmt::Debug,
     io,
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 use tokio::sync::Mutex;
 use tracing_futures::Instrument;
-use std::io::{Error, ErrorKind};
 type Ino = u64;
 
 //noinspection RsUnresolvedReference
@@ -51,6 +53,10 @@ impl INodeTable {
     fn get(&self, ino: Ino) -> Option<Arc<Mutex<INode>>> {
         self.map.get(&ino).cloned()
     }
+
+    fn remove(&mut self, ino: Ino) -> Option<Arc<Mutex<INode>>> {
+        self.map.remove(&ino)
+    }
 }
 
 #[derive(Debug)]
@@ -77,20 +83,48 @@ struct INode {
     attr: FileAttr,
     xattrs: HashMap<OsString, Arc<Vec<u8>>>,
     refcount: u64,
-    links: u64,
     kind: INodeKind,
+    /// Listing lacked size or mtime, ask the backend on lookup.
+    needs_stat: bool,
 }
 
 #[derive(Debug)]
 enum INodeKind {
-    RegularFile(Vec<u8>),
+    /// Content lives in `MemFS::blocks` and the backend.
+    RegularFile(Listed),
     Directory(Directory),
 }
 
+/// What the last listing told about a file. Refreshed listings are compared
+/// with it rather than with the attributes, which stat may have made more
+/// precise.
+#[derive(Debug, Clone, PartialEq)]
+struct Listed {
+    size: Option<u64>,
+    mtime: Option<String>,
+    etag: Option<String>,
+}
+
+impl Listed {
+    fn of(r_entry: &RemoteEntry) -> Self {
+        Self {
+            size: r_entry.size,
+            mtime: r_entry.mtime.clone(),
+            etag: r_entry.etag.clone(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 struct Directory {
     children: HashMap<OsString, Ino>,
     parent: Option<Ino>,
+    /// Children came from the index manifest, no need to list remotely.
+    complete: bool,
+    /// When the children were last brought in line with the server.
+    listed: Option<Instant>,
+    /// Sent along when listing again to learn whether anything changed.
+    validators: Validators,
 }
 
 impl Directory {
@@ -122,10 +156,24 @@ struct DirHandle {
     entries: Vec<Arc<DirEntry>>,
 }
 
+/// Where reads through an open file were heading, to read ahead of
+/// sequential ones. Kept per file handle, so players reading the same file
+/// twice don't break each other's window.
+#[derive(Debug, Default)]
+struct Readahead {
+    /// Offset the next read starts at when access is sequential.
+    next: u64,
+    /// Bytes fetched past a missed read, grows while reads stay sequential.
+    window: u64,
+    /// The last read started where the one before ended.
+    sequential: bool,
+    /// The next file was prefetched since the last seek.
+    prefetched: bool,
+}
+
 #[derive(Debug)]
 struct FileInodeMap {
     parent: Ino,
-    ino: Ino,
     path: PathBuf,
 }
 
@@ -134,32 +182,26 @@ struct FileInodeMap {
 //noinspection RsUnresolvedReference
 #[derive(Debug)]
 pub struct MemFS {
-    http: client::HTTP,
+    backend: Arc<dyn ListingBackend>,
     inodes: Mutex<INodeTable>,
-    f_ino_map: Mutex<Vec<FileInodeMap>>,
+    f_ino_map: Mutex<HashMap<Ino, FileInodeMap>>,
     ttl: Duration,
     dir_handles: Mutex<Slab<Arc<Mutex<DirHandle>>>>,
     cfg: config::Config,
-    metrix: Metrix,
-}
-
-#[derive(Debug)]
-struct Metrix {
-    rx: u64,
-}
-
-impl Metrix {
-    fn new() -> Metrix {
-        Metrix{
-          rx: 0,
-        }
-    }
+    /// Recently read blocks of files.
+    blocks: Arc<Mutex<MemCache>>,
+    /// Readahead state of open files by file handle.
+    readahead: Mutex<Slab<Readahead>>,
 }
 
 impl MemFS {
     //noinspection RsUnresolvedReference
     //noinspection RsUnresolvedReference
     pub fn new(cfg: &config::Config) -> Self {
+        Self::with_backend(cfg, client::backend(cfg))
+    }
+
+    pub fn with_backend(cfg: &config::Config, backend: Arc<dyn ListingBackend>) -> Self {
         let mut inodes = INodeTable::new();
         //let self.cfg = cfg;
         //let entries = http::list_directory(&cfg.server, &cfg.username, &cfg.password, "/").await;
@@ -172,26 +214,27 @@ impl MemFS {
                 attr
             },
             xattrs: HashMap::new(),
-            refcount: u64::max_value() / 2,
-            links: u64::max_value() / 2,
+            refcount: u64::MAX / 2,
             kind: INodeKind::Directory(Directory {
                 children: HashMap::new(),
                 parent: None,
+                complete: false,
+                listed: None,
+                validators: Validators::default(),
             }),
+            needs_stat: false,
         });
 
         Self {
-            http: HTTP::new(
-                cfg.server.clone(),
-                cfg.username.clone(),
-                cfg.password.clone(),
-            ),
+            backend,
             inodes: Mutex::new(inodes),
-            f_ino_map: Mutex::new(Vec::new()),
+            f_ino_map: Mutex::new(HashMap::new()),
             dir_handles: Mutex::default(),
-            ttl: Duration::from_secs(60 * 60 * 24),
+            // The kernel asks again once listings may have changed.
+            ttl: Duration::from_secs(cfg.listing_ttl),
             cfg: cfg.clone(),
-            metrix: Metrix::new(),
+            blocks: Arc::new(Mutex::new(MemCache::new(cfg.memory_cache))),
+            readahead: Mutex::default(),
         }
     }
 
@@ -216,7 +259,7 @@ impl MemFS {
             _ => return Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
         };
 
-        let child_ino = parent.children.get(&*name).copied().ok_or_else(no_entry)?;
+        let child_ino = parent.children.get(name).copied().ok_or_else(no_entry)?;
         let child = inodes.get(child_ino).unwrap_or_else(|| unreachable!());
         let mut child = child.lock().await;
         child.refcount += 1;
@@ -260,7 +303,7 @@ impl MemFS {
             let mut vec_full_uri: Vec<PathBuf> = Vec::new();
             let mut inode = parent_ino;
             loop {
-                let p = self.inode_to_name(inode).await.unwrap();
+                let p = self.inode_to_name(inode).await.ok_or_else(no_entry)?;
                 if p.1 != 0 {
                     vec_full_uri.push(p.0);
                     inode = p.1;
@@ -286,10 +329,7 @@ impl MemFS {
                 debug!("name_to_inode: p_inode - '{:?}' name - '{:?}'", p_inode, name);
 
                 match &inode.kind {
-                    INodeKind::Directory(ref dir) => match dir.children.get(name) {
-                        Some(name) => Some(name.clone()),
-                        None => None,
-                    },
+                    INodeKind::Directory(ref dir) => dir.children.get(name).copied(),
                     _ => None,
                 }
             }
@@ -299,26 +339,37 @@ impl MemFS {
 
     async fn do_lookup(&self, op: &op::Lookup<'_>) -> io::Result<ReplyEntry> {
         debug!("do_lookup: {:?}", op);
+        // New files show up once the listing of the parent is refreshed.
+        let parent_path = self.full_path(op.parent()).await?;
+        if let Err(e) = self.refresh(parent_path.clone(), op.parent()).await {
+            warn!("Can't refresh listing, using the known one. {}", e);
+        }
         match self.name_to_inode(op.parent(), op.name()).await {
             Some(f_inode) => {
                 let inodes = self.inodes.lock().await;
                 let inode = inodes.get(f_inode).ok_or_else(no_entry)?;
                 let inode = inode.lock().await;
                 match &inode.kind {
+                    INodeKind::Directory(dir) if dir.complete => {
+                        drop(inode);
+                        drop(inodes);
+                    }
                     INodeKind::Directory(_) => {
                         drop(inode);
                         drop(inodes);
-                        let mut file_path = self.full_path(op.parent()).await.unwrap();
-                        file_path.push(op.name());
-                        // self.fetch_remote(file_path, f_inode).await.unwrap();
-                        match self.fetch_remote(file_path, f_inode).await {
-                            Err(_) => return Err(io::Error::from_raw_os_error(libc::ENODATA)),
-                            _ => {}
+                        let file_path = parent_path.join(op.name());
+                        if self.refresh(file_path, f_inode).await.is_err() {
+                            return Err(io::Error::from_raw_os_error(libc::ENODATA));
                         }
                     }
                     _ => {
+                        let needs_stat = inode.needs_stat;
                         drop(inode);
                         drop(inodes);
+                        if needs_stat {
+                            let file_path = parent_path.join(op.name());
+                            self.resolve_attr(file_path, f_inode).await?;
+                        }
                     }
                 };
             }
@@ -328,6 +379,21 @@ impl MemFS {
         self.lookup_inode(op.parent(), op.name()).await
     }
 
+    /// Complete attributes of a file the listing gave a name only for.
+    async fn resolve_attr(&self, path: PathBuf, ino: Ino) -> io::Result<()> {
+        debug!("resolve_attr: {:?}", path);
+        let r_entry = self.backend.stat(path).await?;
+
+        let inodes = self.inodes.lock().await;
+        let inode = inodes.get(ino).ok_or_else(no_entry)?;
+        let mut inode = inode.lock().await;
+        inode.attr.set_mtime(r_entry.parse_rfc2822());
+        inode.attr.set_size(r_entry.size.unwrap_or(0));
+        inode.xattrs.extend(remote_xattrs(&r_entry));
+        inode.needs_stat = false;
+        Ok(())
+    }
+
     async fn do_getattr(&self, op: &op::Getattr<'_>) -> io::Result<ReplyAttr> {
         // debug!("do_getattr: op: {:?}", op);
         let inodes = self.inodes.lock().await;
@@ -341,76 +407,297 @@ impl MemFS {
         Ok(reply)
     }
 
+    /// List the directory `ino` at `path` if it wasn't listed yet or its
+    /// listing is older than `listing_ttl`. Directories of the index
+    /// manifest are left alone.
+    async fn refresh(&self, path: PathBuf, ino: Ino) -> io::Result<()> {
+        {
+            let inodes = self.inodes.lock().await;
+            let inode = inodes.get(ino).ok_or_else(no_entry)?;
+            let inode = inode.lock().await;
+            if let INodeKind::Directory(dir) = &inode.kind {
+                let ttl = Duration::from_secs(self.cfg.listing_ttl);
+                if dir.complete || dir.listed.is_some_and(|listed| listed.elapsed() < ttl) {
+                    return Ok(());
+                }
+            }
+        }
+        self.fetch_remote(path, ino).await
+    }
+
+    /// List the directory `parent` at `path` and bring its children in line
+    /// with the server. Files and directories gone from the listing are
+    /// dropped unless the listing is partial, changed files get new
+    /// attributes and lose cached blocks.
     pub async fn fetch_remote(&self, path: PathBuf, parent: u64) -> io::Result<()> {
-        let remote_entries = match self.http.list(path).await {
-            Ok(remote_entries) => remote_entries,
-            Err (e) => return Err(Error::new(ErrorKind::Other, format!("HTTP {:?}: {}", e.status(), e)))
+        let (children, validators) = {
+            let inodes = self.inodes.lock().await;
+            let inode = inodes.get(parent).ok_or_else(no_entry)?;
+            let inode = inode.lock().await;
+            match &inode.kind {
+                INodeKind::Directory(dir) => (dir.children.clone(), dir.validators.clone()),
+                _ => return Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+            }
         };
-        for r_entry in remote_entries.iter() {
-            match &r_entry.r#type {
-                Some(r#type) => match r#type.as_str() {
-                    "file" => {
-                        let f_name = r_entry.name.as_ref().unwrap();
-                        let mut inode_map = self.f_ino_map.lock().await;
-                        let mut full_name = self.full_path(parent).await.unwrap();
-                        full_name.push(PathBuf::from(f_name));
-                        let _x = self.make_node(parent, OsStr::new(f_name.as_str()), |entry| INode {
-                            attr: {
-                                debug!("fetch_remote: Adding file {:?}", full_name);
-                                inode_map.push(FileInodeMap {
-                                    parent,
-                                    ino: entry.ino(),
-                                    path: full_name,
-                                });
-                                let mut attr = FileAttr::default();
-                                attr.set_ino(entry.ino());
-                                attr.set_mtime(r_entry.parse_rfc2822());
-                                attr.set_size(r_entry.size.unwrap());
-                                attr.set_nlink(1);
-                                attr.set_mode(libc::S_IFREG | 0o444);
-                                attr
-                            },
-                            xattrs: HashMap::new(),
-                            refcount: 1,
-                            links: 1,
-                            kind: INodeKind::RegularFile(vec![]),
-                        })
-                        .await;
-                    }
-                    "directory" => {
-                        let f_name = r_entry.name.as_ref().unwrap();
-                        let _x = self.make_node(parent, OsStr::new(f_name.as_str()), |entry| INode {
-                            attr: {
-                                debug!("fetch_remote: Adding directory {:?} - {:?}", f_name, parent);
-                                let mut attr = FileAttr::default();
-                                attr.set_ino(entry.ino());
-                                attr.set_mtime(r_entry.parse_rfc2822());
-                                attr.set_nlink(1);
-                                attr.set_mode(libc::S_IFDIR | 0o755);
-                                attr
-                            },
-                            xattrs: HashMap::new(),
-                            refcount: u64::max_value() / 2,
-                            links: u64::max_value() / 2,
-                            kind: INodeKind::Directory(Directory {
-                                children: HashMap::new(),
-                                parent: Some(parent),
-                            }),
-                        })
-                        .await;
+        let listing = self.backend.list_changed(path.clone(), &validators).await?;
+        let validators = match listing {
+            Some(listing) => {
+                let by_name: HashMap<&str, &RemoteEntry> = listing
+                    .entries
+                    .iter()
+                    .filter_map(|r_entry| Some((r_entry.name.as_deref()?, r_entry)))
+                    .collect();
+                for (name, ino) in children {
+                    let kept = match name.to_str().and_then(|name| by_name.get(name)) {
+                        Some(r_entry) => self.update_node(ino, r_entry).await,
+                        // A server failed to list it, it may still be there.
+                        None => listing.partial,
+                    };
+                    if !kept {
+                        self.remove_node(parent, &name, ino).await;
                     }
-                    &_ => {}
-                },
-                None => {}
+                }
+                for r_entry in listing.entries.iter() {
+                    self.add_remote(parent, &path, r_entry).await;
+                }
+                listing.validators
             }
+            None => validators,
+        };
+
+        let inodes = self.inodes.lock().await;
+        let inode = inodes.get(parent).ok_or_else(no_entry)?;
+        if let INodeKind::Directory(dir) = &mut inode.lock().await.kind {
+            dir.listed = Some(Instant::now());
+            dir.validators = validators;
         }
         Ok(())
     }
 
+    /// Update the inode `ino` from its entry in a new listing. Returns
+    /// `false` when it has to be made anew, e.g. a file became a directory.
+    async fn update_node(&self, ino: Ino, r_entry: &RemoteEntry) -> bool {
+        let inodes = self.inodes.lock().await;
+        let inode = match inodes.get(ino) {
+            Some(inode) => inode,
+            None => return false,
+        };
+        let mut inode = inode.lock().await;
+        match (&inode.kind, r_entry.r#type.as_deref()) {
+            (INodeKind::Directory(_), Some("directory")) => {
+                if r_entry.mtime.is_some() {
+                    inode.attr.set_mtime(r_entry.parse_rfc2822());
+                }
+                true
+            }
+            (INodeKind::RegularFile(listed), Some("file")) => {
+                let now = Listed::of(r_entry);
+                if *listed == now {
+                    return true;
+                }
+                debug!("update_node: {:?} changed on the server", r_entry.name);
+                // What the listing leaves out is asked for again, the old
+                // values stay until then.
+                if r_entry.mtime.is_some() {
+                    inode.attr.set_mtime(r_entry.parse_rfc2822());
+                }
+                if let Some(size) = r_entry.size {
+                    inode.attr.set_size(size);
+                }
+                inode.xattrs.extend(remote_xattrs(r_entry));
+                inode.needs_stat = r_entry.size.is_none() || r_entry.mtime.is_none();
+                inode.kind = INodeKind::RegularFile(now);
+                drop(inode);
+                drop(inodes);
+                self.blocks.lock().await.forget(ino);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Drop the child `name` of the directory `parent` and everything below.
+    async fn remove_node(&self, parent: Ino, name: &OsStr, ino: Ino) {
+        debug!("remove_node: {:?} is gone from the server", name);
+        let mut inodes = self.inodes.lock().await;
+        if let Some(parent) = inodes.get(parent) {
+            if let INodeKind::Directory(dir) = &mut parent.lock().await.kind {
+                dir.children.remove(name);
+            }
+        }
+        let mut gone = vec![ino];
+        let mut removed = Vec::new();
+        while let Some(ino) = gone.pop() {
+            if let Some(inode) = inodes.remove(ino) {
+                if let INodeKind::Directory(dir) = &inode.lock().await.kind {
+                    gone.extend(dir.children.values());
+                }
+                removed.push(ino);
+            }
+        }
+        drop(inodes);
+
+        let mut inode_map = self.f_ino_map.lock().await;
+        let mut blocks = self.blocks.lock().await;
+        for ino in removed {
+            inode_map.remove(&ino);
+            blocks.forget(ino);
+        }
+    }
+
+    /// Create the inode `r_entry` describes in the directory `parent` found
+    /// at remote `dir_path`. Returns the number of the new inode.
+    async fn add_remote(&self, parent: Ino, dir_path: &Path, r_entry: &RemoteEntry) -> Option<Ino> {
+        let f_name = r_entry.name.as_ref()?;
+        let mut ino = None;
+        match r_entry.r#type.as_deref()? {
+            "file" => {
+                let mut full_name = dir_path.to_path_buf();
+                full_name.push(PathBuf::from(f_name));
+                let mut inode_map = self.f_ino_map.lock().await;
+                let _x = self.make_node(parent, OsStr::new(f_name.as_str()), |entry| INode {
+                    attr: {
+                        debug!("fetch_remote: Adding file {:?}", full_name);
+                        ino = Some(entry.ino());
+                        inode_map.insert(entry.ino(), FileInodeMap {
+                            parent,
+                            path: full_name,
+                        });
+                        let mut attr = FileAttr::default();
+                        attr.set_ino(entry.ino());
+                        attr.set_mtime(r_entry.parse_rfc2822());
+                        attr.set_size(r_entry.size.unwrap_or(0));
+                        attr.set_nlink(1);
+                        attr.set_mode(libc::S_IFREG | 0o444);
+                        attr
+                    },
+                    xattrs: remote_xattrs(r_entry),
+                    refcount: 1,
+                    kind: INodeKind::RegularFile(Listed::of(r_entry)),
+                    // Some listings carry names only, the rest comes from stat.
+                    needs_stat: r_entry.size.is_none() || r_entry.mtime.is_none(),
+                })
+                .await;
+            }
+            "directory" => {
+                let _x = self.make_node(parent, OsStr::new(f_name.as_str()), |entry| INode {
+                    attr: {
+                        debug!("fetch_remote: Adding directory {:?} - {:?}", f_name, parent);
+                        ino = Some(entry.ino());
+                        let mut attr = FileAttr::default();
+                        attr.set_ino(entry.ino());
+                        attr.set_mtime(r_entry.parse_rfc2822());
+                        attr.set_nlink(1);
+                        attr.set_mode(libc::S_IFDIR | 0o755);
+                        attr
+                    },
+                    xattrs: remote_xattrs(r_entry),
+                    refcount: u64::MAX / 2,
+                    kind: INodeKind::Directory(Directory {
+                        children: HashMap::new(),
+                        parent: Some(parent),
+                        complete: false,
+                        listed: None,
+                        validators: Validators::default(),
+                    }),
+                    needs_stat: false,
+                })
+                .await;
+            }
+            _ => {}
+        }
+        ino
+    }
+
+    /// Populate the inode table from the index manifest of the server.
+    ///
+    /// Returns `false` when there is no usable manifest and directories have
+    /// to be listed one by one instead.
+    pub async fn load_manifest(&self) -> bool {
+        if !self.cfg.manifest {
+            return false;
+        }
+        let manifest = match Manifest::fetch(&*self.backend).await {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                info!("No index manifest found, listing directories on demand. {}", e);
+                return false;
+            }
+        };
+        if manifest.is_stale(self.cfg.manifest_max_age) {
+            warn!("Index manifest is stale, listing directories on demand.");
+            return false;
+        }
+        info!("Loading {} entries from index manifest", manifest.entries.len());
+
+        let mut dirs: HashMap<PathBuf, Ino> = HashMap::new();
+        dirs.insert(PathBuf::from("/"), 1);
+        for m_entry in manifest.sorted_entries() {
+            let path = Path::new("/").join(&m_entry.path);
+            let (dir_path, name) = match (path.parent(), path.file_name()) {
+                (Some(dir_path), Some(name)) => (dir_path.to_path_buf(), name.to_string_lossy()),
+                _ => continue,
+            };
+            let parent = self.make_dirs(&mut dirs, &dir_path).await;
+            let mut r_entry = m_entry.entry.clone();
+            r_entry.name = Some(name.into_owned());
+            let ino = match self.add_remote(parent, &dir_path, &r_entry).await {
+                Some(ino) => ino,
+                None => continue,
+            };
+            if r_entry.r#type.as_deref() == Some("directory") {
+                dirs.insert(path, ino);
+            }
+            if let Some(sha256) = &m_entry.sha256 {
+                let inodes = self.inodes.lock().await;
+                if let Some(inode) = inodes.get(ino) {
+                    let xattr = Arc::new(sha256.clone().into_bytes());
+                    inode.lock().await.xattrs.insert(OsString::from("user.sha256"), xattr);
+                }
+            }
+        }
+
+        let inodes = self.inodes.lock().await;
+        for ino in dirs.values() {
+            if let Some(inode) = inodes.get(*ino) {
+                if let INodeKind::Directory(ref mut dir) = inode.lock().await.kind {
+                    dir.complete = true;
+                }
+            }
+        }
+        true
+    }
+
+    /// Inode of directory `path`, creating it and its missing ancestors the
+    /// manifest has no entries for.
+    async fn make_dirs(&self, dirs: &mut HashMap<PathBuf, Ino>, path: &Path) -> Ino {
+        if let Some(ino) = dirs.get(path) {
+            return *ino;
+        }
+        let dir_path = path.parent().unwrap_or_else(|| Path::new("/"));
+        let parent = Box::pin(self.make_dirs(dirs, dir_path)).await;
+        let name = path.file_name().map(|name| name.to_string_lossy().into_owned());
+        let r_entry = RemoteEntry {
+            name: name.clone(),
+            r#type: Some(String::from("directory")),
+            ..RemoteEntry::default()
+        };
+        let ino = match self.add_remote(parent, dir_path, &r_entry).await {
+            Some(ino) => ino,
+            None => self
+                .name_to_inode(parent, OsStr::new(&name.unwrap_or_default()))
+                .await
+                .unwrap_or(parent),
+        };
+        dirs.insert(path.to_path_buf(), ino);
+        ino
+    }
+
     async fn inode_to_name(&self, inode: u64) -> Option<(PathBuf, u64)> {
         let inodes = self.inodes.lock().await;
 
-        let inode_mutex = inodes.get(inode).ok_or_else(no_entry).unwrap();
+        // Gone when the directory disappeared from the server.
+        let inode_mutex = inodes.get(inode)?;
 
         let inode_mutex = inode_mutex.lock().await;
 
@@ -419,7 +706,7 @@ impl MemFS {
         let ret = match &inode_mutex.kind {
             INodeKind::Directory(dir) => match dir.parent {
                 Some(parent) => {
-                    let par_inode = inodes.get(parent).ok_or_else(no_entry).unwrap();
+                    let par_inode = inodes.get(parent)?;
                     let par_inode = par_inode.lock().await;
 
                     parent_ino = par_inode.attr.ino();
@@ -448,6 +735,10 @@ impl MemFS {
     //noinspection RsUnresolvedReference
     async fn do_opendir(&self, op: &op::Opendir<'_>) -> io::Result<ReplyOpen> {
         debug!("do_opendir: {:?}", op);
+        let path = self.full_path(op.ino()).await?;
+        if let Err(e) = self.refresh(path, op.ino()).await {
+            warn!("Can't refresh listing, using the known one. {}", e);
+        }
 
         let mut dirs = self.dir_handles.lock().await;
         let inodes = self.inodes.lock().await;
@@ -487,7 +778,7 @@ impl MemFS {
             .iter()
             .skip(op.offset() as usize)
             .take_while(|entry| {
-                let entry: &DirEntry = &*entry;
+                let entry: &DirEntry = entry;
                 total_len += entry.as_ref().len() as u32;
                 total_len < op.size()
             })
@@ -497,6 +788,27 @@ impl MemFS {
         Ok(entries)
     }
 
+    async fn do_open(&self, op: &op::Open<'_>) -> io::Result<ReplyOpen> {
+        debug!("do_open: {:?}", op);
+        Ok(ReplyOpen::new(self.open(op.ino()).await?))
+    }
+
+    /// Open the file `ino`, returning the new file handle.
+    async fn open(&self, ino: Ino) -> io::Result<u64> {
+        if !self.f_ino_map.lock().await.contains_key(&ino) {
+            return Err(no_entry());
+        }
+        Ok(self.readahead.lock().await.insert(Readahead::default()) as u64)
+    }
+
+    async fn do_release(&self, op: &op::Release<'_>) -> io::Result<()> {
+        let mut files = self.readahead.lock().await;
+        if files.contains(op.fh() as usize) {
+            files.remove(op.fh() as usize);
+        }
+        Ok(())
+    }
+
     async fn do_releasedir(&self, op: &op::Releasedir<'_>) -> io::Result<()> {
         let mut dirs = self.dir_handles.lock().await;
 
@@ -506,44 +818,272 @@ impl MemFS {
         Ok(())
     }
 
-    async fn do_read(&self, op: &op::Read<'_>) -> io::Result<impl Reply + Debug> {
-        let full_path_mutex = self.f_ino_map.lock().await;
-        let mut counter = 0;
-        let full_path = loop {
-            if counter < full_path_mutex.len() {
-                if full_path_mutex[counter].ino == op.ino() {
-                    break full_path_mutex[counter].path.clone();
+    async fn do_getxattr(&self, op: &op::Getxattr<'_>) -> io::Result<impl Reply + Debug> {
+        let inodes = self.inodes.lock().await;
+        let inode = inodes.get(op.ino()).ok_or_else(no_entry)?;
+        let inode = inode.lock().await;
+
+        let value = inode
+            .xattrs
+            .get(op.name())
+            .cloned()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENODATA))?;
+
+        match op.size() {
+            0 => Ok((Some(ReplyXattr::new(value.len() as u32)), None)),
+            size if (size as usize) < value.len() => Err(io::Error::from_raw_os_error(libc::ERANGE)),
+            _ => Ok((None, Some(value))),
+        }
+    }
+
+    async fn do_listxattr(&self, op: &op::Listxattr<'_>) -> io::Result<impl Reply + Debug> {
+        let inodes = self.inodes.lock().await;
+        let inode = inodes.get(op.ino()).ok_or_else(no_entry)?;
+        let inode = inode.lock().await;
+
+        let mut names = Vec::new();
+        for name in inode.xattrs.keys() {
+            names.extend_from_slice(name.as_bytes());
+            names.push(b'\0');
+        }
+
+        match op.size() {
+            0 => Ok((Some(ReplyXattr::new(names.len() as u32)), None)),
+            size if (size as usize) < names.len() => Err(io::Error::from_raw_os_error(libc::ERANGE)),
+            _ => Ok((None, Some(names))),
+        }
+    }
+
+    /// Blocks `first..=last` of the file `ino` at `path`, taken from memory
+    /// where possible. Fewer come back when the file ends earlier.
+    ///
+    /// When some are missing up to `ahead` blocks past `last` are fetched
+    /// along and kept in memory, but not returned.
+    async fn read_blocks(
+        &self,
+        ino: Ino,
+        path: &Path,
+        first: u64,
+        last: u64,
+        ahead: u64,
+    ) -> io::Result<Vec<Arc<Vec<u8>>>> {
+        let block = memcache::BLOCK;
+        let mut blocks: Vec<Option<Arc<Vec<u8>>>> = {
+            let mut cache = self.blocks.lock().await;
+            let mut blocks: Vec<_> = (first..=last)
+                .map(|index| cache.get(ino, index))
+                .collect();
+            if blocks.iter().any(Option::is_none) {
+                let missing = (last + 1..=last + ahead)
+                    .take_while(|index| cache.get(ino, *index).is_none())
+                    .count();
+                blocks.extend((0..missing).map(|_| None));
+            }
+            blocks
+        };
+        fill_runs(&mut blocks, move |i, run| async move {
+            let start = (first + i as u64) * block;
+            let data = self
+                .backend
+                .read(path.to_path_buf(), run * block as usize, start as usize)
+                .await?;
+            let fetched: Vec<Arc<Vec<u8>>> = data
+                .chunks(block as usize)
+                .map(|b| Arc::new(b.to_vec()))
+                .collect();
+            let mut cache = self.blocks.lock().await;
+            for (j, b) in fetched.iter().enumerate() {
+                cache.insert(ino, first + (i + j) as u64, b.clone());
+            }
+            Ok(fetched)
+        })
+        .await?;
+        blocks.truncate((last - first + 1) as usize);
+        let mut found = Vec::with_capacity(blocks.len());
+        for b in blocks.into_iter().flatten() {
+            let full = b.len() as u64 == block;
+            found.push(b);
+            if !full {
+                break;
+            }
+        }
+        Ok(found)
+    }
+
+    /// Bytes to read ahead of the read of `size` bytes at `offset` through
+    /// the file handle `fh`. The window doubles with every sequential read up
+    /// to `readahead` of the config and is dropped on a seek. Blocks read
+    /// ahead are kept in the memory cache, so the window never outgrows it.
+    async fn readahead(&self, fh: u64, offset: u64, size: u64) -> u64 {
+        let limit = self.cfg.readahead.min(self.cfg.memory_cache);
+        let mut files = self.readahead.lock().await;
+        let file = match files.get_mut(fh as usize) {
+            Some(file) => file,
+            None => return 0,
+        };
+        file.sequential = offset == file.next && offset > 0;
+        if !file.sequential {
+            file.prefetched = false;
+        }
+        file.window = if file.sequential && limit > 0 {
+            (file.window * 2).max(memcache::BLOCK).min(limit)
+        } else {
+            0
+        };
+        file.next = offset + size;
+        file.window
+    }
+
+    /// Start fetching the beginning of the file following `ino` in its
+    /// directory once `ino` was read sequentially through `fh` up to `end`,
+    /// within `prefetch_size` of its end, so players going on to the next
+    /// track don't stall. Fetching the rest of the file takes about as long
+    /// as fetching the start of the next one. Reads of tags at the end right
+    /// after opening don't count.
+    async fn prefetch_next(&self, ino: Ino, fh: u64, end: u64) {
+        if !self.cfg.prefetch_next || self.cfg.memory_cache == 0 {
+            return;
+        }
+        let size = {
+            let inodes = self.inodes.lock().await;
+            match inodes.get(ino) {
+                Some(inode) => inode.lock().await.attr.size(),
+                None => return,
+            }
+        };
+        if end + self.cfg.prefetch_size.max(memcache::BLOCK) < size {
+            return;
+        }
+        {
+            let mut files = self.readahead.lock().await;
+            let file = match files.get_mut(fh as usize) {
+                Some(file) => file,
+                None => return,
+            };
+            if file.prefetched || !file.sequential {
+                return;
+            }
+            file.prefetched = true;
+        }
+        let (next, path) = match self.next_file(ino).await {
+            Some(next) => next,
+            None => return,
+        };
+        let block = memcache::BLOCK;
+        let count = self.cfg.prefetch_size.div_ceil(block);
+        if count == 0 {
+            return;
+        }
+        let backend = self.backend.clone();
+        let blocks = self.blocks.clone();
+        tokio::spawn(async move {
+            if blocks.lock().await.get(next, 0).is_some() {
+                return;
+            }
+            debug!("Prefetching {}", path.display());
+            match backend.read(path.clone(), (count * block) as usize, 0).await {
+                Ok(data) => {
+                    let mut cache = blocks.lock().await;
+                    for (index, b) in data.chunks(block as usize).enumerate() {
+                        cache.insert(next, index as u64, Arc::new(b.to_vec()));
+                    }
                 }
-            } else {
-                break PathBuf::from("");
+                Err(e) => debug!("Can't prefetch {}. {}", path.display(), e),
             }
-            counter += 1;
+        });
+    }
+
+    /// File after `ino` in its directory sorted by name, with its path.
+    async fn next_file(&self, ino: Ino) -> Option<(Ino, PathBuf)> {
+        let (parent, name) = {
+            let files = self.f_ino_map.lock().await;
+            let file = files.get(&ino)?;
+            (file.parent, file.path.file_name()?.to_os_string())
+        };
+        let mut siblings: Vec<(OsString, Ino)> = {
+            let inodes = self.inodes.lock().await;
+            let parent = inodes.get(parent)?;
+            let parent = parent.lock().await;
+            match &parent.kind {
+                INodeKind::Directory(dir) => dir
+                    .children
+                    .iter()
+                    .filter(|(sibling, _)| **sibling > name)
+                    .map(|(sibling, ino)| (sibling.clone(), *ino))
+                    .collect(),
+                _ => return None,
+            }
+        };
+        siblings.sort();
+        // Only files are in `f_ino_map`, directories are passed over.
+        let files = self.f_ino_map.lock().await;
+        siblings
+            .into_iter()
+            .find_map(|(_, ino)| files.get(&ino).map(|file| (ino, file.path.clone())))
+    }
+
+    async fn do_read(&self, op: &op::Read<'_>) -> io::Result<impl Reply + Debug> {
+        self.read(op.ino(), op.fh(), op.offset(), op.size() as usize).await
+    }
+
+    /// Read `size` bytes at `offset` of `ino` opened as `fh`.
+    async fn read(&self, ino: Ino, fh: u64, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+        let full_path_mutex = self.f_ino_map.lock().await;
+        let full_path = match full_path_mutex.get(&ino) {
+            Some(file) => file.path.clone(),
+            // Removed from the server while still open.
+            None => return Err(io::Error::from_raw_os_error(libc::ESTALE)),
         };
-        let offset = op.offset() as usize;
-        let size = op.size() as usize;
         drop(full_path_mutex);
-        let chunk = match self.http.read(full_path, size, offset).await {
-            Ok(data) => data,
-            Err (e) => {
-                let msg =  format!("HTTP {:?}: {}", e.status(), e);
-                error!("Read error. {:?}", msg);
-                return Err(Error::new(ErrorKind::Other, msg))
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        let block = memcache::BLOCK;
+        let first = offset / block;
+        let last = (offset + size as u64 - 1) / block;
+        let blocks_in_file = {
+            let inodes = self.inodes.lock().await;
+            match inodes.get(ino) {
+                Some(inode) => inode.lock().await.attr.size().div_ceil(block),
+                None => 0,
             }
         };
-        Ok(chunk.to_vec())
+        // Blocks past the end of the file are never asked for.
+        let ahead = (self.readahead(fh, offset, size as u64).await / block)
+            .min(blocks_in_file.saturating_sub(last + 1));
+        let blocks = match self
+            .read_blocks(ino, &full_path, first, last, ahead)
+            .await
+        {
+            Ok(blocks) => blocks,
+            Err(e) => {
+                error!("Read error. {:?}", e.to_string());
+                return Err(e);
+            }
+        };
+        let mut chunk = Vec::with_capacity(size);
+        let mut pos = first * block;
+        for b in blocks {
+            let from = offset.saturating_sub(pos).min(b.len() as u64) as usize;
+            let to = (offset + size as u64 - pos).min(b.len() as u64) as usize;
+            chunk.extend_from_slice(&b[from..to]);
+            pos += block;
+        }
+        self.prefetch_next(ino, fh, offset + chunk.len() as u64).await;
+        Ok(chunk)
     }
 }
 
 #[polyfuse::async_trait]
 impl Filesystem for MemFS {
     #[allow(clippy::cognitive_complexity)]
-    async fn call<'a, 'cx, T: ?Sized>(
+    async fn call<'a, 'cx, T>(
         &'a self,
         cx: &'a mut Context<'cx, T>,
         op: Operation<'cx>,
     ) -> io::Result<()>
     where
-        T: Reader + Writer + Send + Unpin,
+        T: ?Sized + Reader + Writer + Send + Unpin,
     {
         let span = tracing::debug_span!("MemFS::call", unique = cx.unique());
         span.in_scope(|| tracing::debug!(?op));
@@ -570,7 +1110,11 @@ impl Filesystem for MemFS {
             Operation::Opendir(op) => try_reply!(self.do_opendir(&op)),
             Operation::Readdir(op) => try_reply!(self.do_readdir(&op)),
             Operation::Releasedir(op) => try_reply!(self.do_releasedir(&op)),
+            Operation::Open(op) => try_reply!(self.do_open(&op)),
+            Operation::Release(op) => try_reply!(self.do_release(&op)),
             Operation::Read(op) => try_reply!(self.do_read(&op)),
+            Operation::Getxattr(op) => try_reply!(self.do_getxattr(&op)),
+            Operation::Listxattr(op) => try_reply!(self.do_listxattr(&op)),
             _ => {
                 span.in_scope(|| tracing::debug!("NOSYS"));
                 Ok(())
@@ -579,6 +1123,15 @@ impl Filesystem for MemFS {
     }
 }
 
+/// Extended attributes carrying what the listing knows beyond `FileAttr`.
+fn remote_xattrs(r_entry: &RemoteEntry) -> HashMap<OsString, Arc<Vec<u8>>> {
+    let mut xattrs = HashMap::new();
+    if let Some(etag) = &r_entry.etag {
+        xattrs.insert(OsString::from("user.etag"), Arc::new(etag.clone().into_bytes()));
+    }
+    xattrs
+}
+
 fn no_entry() -> io::Error {
     io::Error::from_raw_os_error(libc::ENOENT)
 }
@@ -586,3 +1139,202 @@ fn no_entry() -> io::Error {
 fn unknown_error() -> io::Error {
     io::Error::from_raw_os_error(libc::EIO)
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::archive::tests::Bytes;
+    use crate::client::{file_name, Listing};
+    use crate::listing::remote_entry;
+    use crate::union::{ConflictPolicy, Union};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Server holding files at the root which can change between listings.
+    #[derive(Debug, Default)]
+    pub struct Server {
+        files: std::sync::Mutex<Vec<(String, Vec<u8>)>>,
+        /// Listings are answered with `304 Not Modified`.
+        pub unchanged: AtomicBool,
+        /// Every request fails.
+        pub down: AtomicBool,
+    }
+
+    impl Server {
+        pub fn new(files: &[(&str, &[u8])]) -> Self {
+            let server = Self::default();
+            for (name, data) in files {
+                server.put(name, data);
+            }
+            server
+        }
+
+        pub fn put(&self, name: &str, data: &[u8]) {
+            let mut files = self.files.lock().unwrap();
+            files.retain(|(file, _)| file != name);
+            files.push((name.to_string(), data.to_vec()));
+        }
+
+        pub fn remove(&self, name: &str) {
+            self.files.lock().unwrap().retain(|(file, _)| file != name);
+        }
+
+        fn up(&self) -> io::Result<()> {
+            match self.down.load(Ordering::SeqCst) {
+                true => Err(io::Error::other("Server is down")),
+                false => Ok(()),
+            }
+        }
+
+        fn data(&self, path: &Path) -> io::Result<Vec<u8>> {
+            self.up()?;
+            let name = path.file_name().unwrap_or_default();
+            let files = self.files.lock().unwrap();
+            files
+                .iter()
+                .find(|(file, _)| OsStr::new(file) == name)
+                .map(|(_, data)| data.clone())
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+        }
+    }
+
+    #[polyfuse::async_trait]
+    impl ListingBackend for Server {
+        async fn list(&self, path: PathBuf) -> io::Result<Vec<RemoteEntry>> {
+            self.up()?;
+            if path != Path::new("/") {
+                return Err(io::Error::from(io::ErrorKind::NotFound));
+            }
+            let files = self.files.lock().unwrap();
+            Ok(files
+                .iter()
+                .map(|(name, data)| remote_entry(name.clone(), false, None, Some(data.len() as u64)))
+                .collect())
+        }
+
+        async fn list_changed(&self, path: PathBuf, _: &Validators) -> io::Result<client::Changed> {
+            self.up()?;
+            if self.unchanged.load(Ordering::SeqCst) {
+                return Ok(None);
+            }
+            Ok(Some(Listing::full(self.list(path).await?)))
+        }
+
+        async fn read(&self, path: PathBuf, size: usize, offset: usize) -> io::Result<Vec<u8>> {
+            let data = self.data(&path)?;
+            let offset = offset.min(data.len());
+            let end = (offset + size).min(data.len());
+            Ok(data[offset..end].to_vec())
+        }
+
+        async fn stat(&self, path: PathBuf) -> io::Result<RemoteEntry> {
+            let data = self.data(&path)?;
+            Ok(remote_entry(file_name(&path).unwrap_or_default(), false, None, Some(data.len() as u64)))
+        }
+    }
+
+    /// Filesystem over `backend` with its root listed.
+    async fn mounted(backend: Arc<dyn ListingBackend>) -> MemFS {
+        let cfg = config::Config {
+            memory_cache: 64 * 1024 * 1024,
+            readahead: 4 * 1024 * 1024,
+            listing_ttl: 60,
+            ..Default::default()
+        };
+        let fs = MemFS::with_backend(&cfg, backend);
+        fs.fetch_remote(PathBuf::from("/"), 1).await.unwrap();
+        fs
+    }
+
+    /// Inode of the file at `path`.
+    async fn file(fs: &MemFS, path: &str) -> Option<Ino> {
+        let files = fs.f_ino_map.lock().await;
+        files
+            .iter()
+            .find(|(_, file)| file.path == Path::new(path))
+            .map(|(ino, _)| *ino)
+    }
+
+    #[tokio::test]
+    async fn readahead_per_handle() {
+        let fs = mounted(Arc::new(Bytes::new("track", &[0; 4096]))).await;
+        let ino = file(&fs, "/track").await.unwrap();
+        let (a, b) = (fs.open(ino).await.unwrap(), fs.open(ino).await.unwrap());
+        let block = memcache::BLOCK;
+
+        // Two readers of the same file don't break each other's window.
+        assert_eq!(fs.readahead(a, 0, block).await, 0);
+        assert_eq!(fs.readahead(b, 0, block).await, 0);
+        assert_eq!(fs.readahead(a, block, block).await, block);
+        assert_eq!(fs.readahead(b, block, block).await, block);
+        assert_eq!(fs.readahead(a, 2 * block, block).await, 2 * block);
+
+        fs.readahead.lock().await.remove(a as usize);
+        assert_eq!(fs.readahead(a, 3 * block, block).await, 0);
+    }
+
+    #[tokio::test]
+    async fn modified_file_drops_blocks() {
+        let server = Arc::new(Server::new(&[("track", &[1; 10])]));
+        let fs = mounted(server.clone()).await;
+        let ino = file(&fs, "/track").await.unwrap();
+        let fh = fs.open(ino).await.unwrap();
+        assert_eq!(fs.read(ino, fh, 0, 100).await.unwrap(), vec![1; 10]);
+
+        server.put("track", &[2; 20]);
+        fs.fetch_remote(PathBuf::from("/"), 1).await.unwrap();
+        assert_eq!(file(&fs, "/track").await, Some(ino));
+        assert_eq!(fs.read(ino, fh, 0, 100).await.unwrap(), vec![2; 20]);
+    }
+
+    #[tokio::test]
+    async fn removed_file_is_stale() {
+        let server = Arc::new(Server::new(&[("track", &[1; 10])]));
+        let fs = mounted(server.clone()).await;
+        let ino = file(&fs, "/track").await.unwrap();
+        let fh = fs.open(ino).await.unwrap();
+
+        server.remove("track");
+        fs.fetch_remote(PathBuf::from("/"), 1).await.unwrap();
+        assert_eq!(file(&fs, "/track").await, None);
+        let err = fs.read(ino, fh, 0, 10).await.unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ESTALE));
+    }
+
+    #[tokio::test]
+    async fn not_modified_keeps_listing() {
+        let server = Arc::new(Server::new(&[("track", &[1; 10])]));
+        let fs = mounted(server.clone()).await;
+        let ino = file(&fs, "/track").await.unwrap();
+
+        server.unchanged.store(true, Ordering::SeqCst);
+        server.remove("track");
+        server.put("new", &[2; 10]);
+        fs.fetch_remote(PathBuf::from("/"), 1).await.unwrap();
+        assert_eq!(file(&fs, "/track").await, Some(ino));
+        assert_eq!(file(&fs, "/new").await, None);
+    }
+
+    #[tokio::test]
+    async fn partial_union_keeps_files() {
+        let first = Arc::new(Server::new(&[("a", &[1; 10])]));
+        let second = Arc::new(Server::new(&[("b", &[2; 10])]));
+        let backends: Vec<Arc<dyn ListingBackend>> = vec![first.clone(), second.clone()];
+        let fs = mounted(Arc::new(Union::new(backends, ConflictPolicy::First))).await;
+        assert!(file(&fs, "/b").await.is_some());
+
+        // Nothing is removed while a server fails, new files still show up.
+        second.down.store(true, Ordering::SeqCst);
+        first.remove("a");
+        first.put("c", &[3; 10]);
+        fs.fetch_remote(PathBuf::from("/"), 1).await.unwrap();
+        assert!(file(&fs, "/a").await.is_some());
+        assert!(file(&fs, "/b").await.is_some());
+        assert!(file(&fs, "/c").await.is_some());
+
+        second.down.store(false, Ordering::SeqCst);
+        second.remove("b");
+        fs.fetch_remote(PathBuf::from("/"), 1).await.unwrap();
+        assert_eq!(file(&fs, "/a").await, None);
+        assert_eq!(file(&fs, "/b").await, None);
+    }
+}
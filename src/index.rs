@@ -0,0 +1,135 @@
+//! `furumi index`: publish a local tree on static hosting without autoindex.
+//!
+//! Every directory may get a listing in NGINX JSON format, and the root gets
+//! the gzipped manifest of the whole tree. Dot files are skipped the same
+//! way NGINX autoindex does.
+
+use crate::client::RemoteEntry;
+use crate::config::IndexArgs;
+use crate::listing::remote_entry;
+use crate::manifest::{Manifest, ManifestEntry, MANIFEST_NAMES, MANIFEST_VERSION};
+use chrono::{DateTime, Utc};
+use flate2::{write::GzEncoder, Compression};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+pub fn run(args: &IndexArgs) -> io::Result<()> {
+    if !args.listings && !args.manifest {
+        warn!("Nothing to write. Pass --listings or drop --no-manifest.");
+        return Ok(());
+    }
+    let mut entries = Vec::new();
+    let root = fs::metadata(&args.root)?;
+    let mut ancestors = HashSet::new();
+    ancestors.insert((root.dev(), root.ino()));
+    walk(
+        args,
+        &args.root,
+        Path::new(""),
+        &mut ancestors,
+        &mut entries,
+    )?;
+    info!(
+        "Indexed {} entries in {}",
+        entries.len(),
+        args.root.display()
+    );
+
+    if args.manifest {
+        let manifest = Manifest {
+            version: MANIFEST_VERSION,
+            generated: Some(Utc::now().to_rfc2822()),
+            entries,
+        };
+        let path = args.root.join(MANIFEST_NAMES[0]);
+        let file = BufWriter::new(File::create(&path)?);
+        let mut gz = GzEncoder::new(file, Compression::default());
+        serde_json::to_writer(&mut gz, &manifest)?;
+        gz.finish()?.flush()?;
+        info!("Written manifest {}", path.display());
+    }
+    Ok(())
+}
+
+/// Index directory `dir` found at `rel` below the root. `ancestors` holds
+/// the device and inode of the directories above, symlinks are followed
+/// but never back into one of them.
+fn walk(
+    args: &IndexArgs,
+    dir: &Path,
+    rel: &Path,
+    ancestors: &mut HashSet<(u64, u64)>,
+    manifest: &mut Vec<ManifestEntry>,
+) -> io::Result<()> {
+    let mut children = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    children.sort_by_key(|child| child.file_name());
+
+    let mut listing = Vec::with_capacity(children.len());
+    for child in children {
+        let name = child.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') || (args.listings && name == args.listing_name) {
+            continue;
+        }
+        let meta = match fs::metadata(child.path()) {
+            Ok(meta) => meta,
+            Err(e) => {
+                warn!("Skipping {}. {}", child.path().display(), e);
+                continue;
+            }
+        };
+        if !meta.is_dir() && !meta.is_file() {
+            continue;
+        }
+        let id = (meta.dev(), meta.ino());
+        if meta.is_dir() && ancestors.contains(&id) {
+            warn!(
+                "Skipping {}. It links back to a parent directory.",
+                child.path().display()
+            );
+            continue;
+        }
+        let mtime = meta.modified().ok().map(DateTime::<Utc>::from);
+        let entry = remote_entry(name.clone(), meta.is_dir(), mtime, Some(meta.len()));
+        let path = rel.join(&name);
+        let sha256 = if args.hash && meta.is_file() {
+            Some(sha256(&child.path())?)
+        } else {
+            None
+        };
+        manifest.push(ManifestEntry {
+            path: path.to_string_lossy().into_owned(),
+            entry: RemoteEntry {
+                name: None,
+                ..entry.clone()
+            },
+            sha256,
+        });
+        listing.push(entry);
+        if meta.is_dir() {
+            ancestors.insert(id);
+            walk(args, &child.path(), &path, ancestors, manifest)?;
+            ancestors.remove(&id);
+        }
+    }
+
+    if args.listings {
+        let path = dir.join(&args.listing_name);
+        let mut file = BufWriter::new(File::create(&path)?);
+        serde_json::to_writer(&mut file, &listing)?;
+        file.flush()?;
+        debug!("Written listing {}", path.display());
+    }
+    Ok(())
+}
+
+fn sha256(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
@@ -0,0 +1,164 @@
+//! Apache `mod_autoindex` listings.
+//!
+//! Both FancyIndexing layouts are understood: `HTMLTable`, where every entry
+//! is a `<tr>` row, and the classic `<pre>` block with one entry per line.
+//! Plain `<ul>` indexes carry names only.
+
+use super::{href_to_name, remote_entry, unescape_html};
+use crate::client::RemoteEntry;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use regex::Regex;
+
+const DATE_FORMATS: [&str; 4] = [
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%d %H:%M",
+    "%d-%b-%Y %H:%M:%S",
+    "%d-%b-%Y %H:%M",
+];
+
+pub fn parse(body: &str) -> Vec<RemoteEntry> {
+    let anchor = Regex::new(r#"(?is)<a\s[^>]*?href\s*=\s*["']([^"']*)["'][^>]*>.*?</a>"#).unwrap();
+    let tag = Regex::new(r"(?s)<[^>]*>").unwrap();
+    let columns = Regex::new(
+        r"(\d{4}-\d{2}-\d{2} \d{2}:\d{2}(?::\d{2})?|\d{2}-[A-Za-z]{3}-\d{4} \d{2}:\d{2}(?::\d{2})?)\s+(\S+)",
+    )
+    .unwrap();
+
+    let mut entries = Vec::new();
+    for record in records(body) {
+        let link = match anchor.captures(record) {
+            Some(link) => link,
+            None => continue,
+        };
+        let (name, is_dir) = match href_to_name(&link[1]) {
+            Some(name) => name,
+            None => continue,
+        };
+        let rest = &record[link.get(0).unwrap().end()..];
+        let rest = unescape_html(&tag.replace_all(rest, " "));
+        let (mtime, size) = match columns.captures(&rest) {
+            Some(cols) => (parse_date(&cols[1]), parse_size(&cols[2])),
+            None => (None, None),
+        };
+        entries.push(remote_entry(name, is_dir, mtime, size));
+    }
+    entries
+}
+
+/// Split the page into chunks holding a single entry each.
+fn records(body: &str) -> Vec<&str> {
+    let lower = body.to_lowercase();
+    if lower.contains("<table") {
+        split_at(body, &lower, "<tr")
+    } else if let Some(start) = lower.find("<pre") {
+        // The first entry may share a line with the header, right after `<hr>`.
+        let end = lower[start..]
+            .find("</pre")
+            .map_or(body.len(), |end| start + end);
+        let line = Regex::new(r"(?i)<hr[^>]*>|\n").unwrap();
+        line.split(&body[start..end]).collect()
+    } else {
+        split_at(body, &lower, "<li")
+    }
+}
+
+fn split_at<'a>(body: &'a str, lower: &str, delimiter: &str) -> Vec<&'a str> {
+    let mut bounds: Vec<usize> = lower.match_indices(delimiter).map(|(i, _)| i).collect();
+    bounds.push(body.len());
+    bounds.windows(2).map(|w| &body[w[0]..w[1]]).collect()
+}
+
+/// Apache prints local server time without a zone, take it as UTC.
+fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+    DATE_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(date, format).ok())
+        .map(|date| Utc.from_utc_datetime(&date))
+}
+
+/// Only exact byte counts are trusted. Abbreviated sizes such as `4.5M` are
+/// dropped so the filesystem asks the server for the real length.
+fn parse_size(size: &str) -> Option<u64> {
+    size.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listing::tests::mtime;
+
+    const TABLE: &str = r#"<!DOCTYPE HTML PUBLIC "-//W3C//DTD HTML 3.2 Final//EN">
+<html>
+ <head>
+  <title>Index of /music</title>
+ </head>
+ <body>
+<h1>Index of /music</h1>
+  <table>
+   <tr><th valign="top"><img src="/icons/blank.gif" alt="[ICO]"></th><th><a href="?C=N;O=D">Name</a></th><th><a href="?C=M;O=A">Last modified</a></th><th><a href="?C=S;O=A">Size</a></th><th><a href="?C=D;O=A">Description</a></th></tr>
+   <tr><th colspan="5"><hr></th></tr>
+<tr><td valign="top"><img src="/icons/back.gif" alt="[PARENTDIR]"></td><td><a href="/">Parent Directory</a></td><td>&nbsp;</td><td align="right">  - </td><td>&nbsp;</td></tr>
+<tr><td valign="top"><img src="/icons/folder.gif" alt="[DIR]"></td><td><a href="Albums/">Albums/</a></td><td align="right">2023-04-01 12:30  </td><td align="right">  - </td><td>&nbsp;</td></tr>
+<tr><td valign="top"><img src="/icons/sound2.gif" alt="[SND]"></td><td><a href="01%20Intro%20%26%20Outro.flac">01 Intro &amp; Outro.flac</a></td><td align="right">2023-04-02 08:15:42  </td><td align="right">4718592 </td><td>&nbsp;</td></tr>
+<tr><td valign="top"><img src="/icons/sound2.gif" alt="[SND]"></td><td><a href="02.flac">02.flac</a></td><td align="right">2023-04-02 08:16  </td><td align="right">4.5M</td><td>&nbsp;</td></tr>
+   <tr><th colspan="5"><hr></th></tr>
+</table>
+</body></html>
+"#;
+
+    const PRE: &str = r#"<html><head><title>Index of /music</title></head><body>
+<h1>Index of /music</h1>
+<pre><img src="/icons/blank.gif" alt="Icon "> <a href="?C=N;O=D">Name</a>                    <a href="?C=M;O=A">Last modified</a>      <a href="?C=S;O=A">Size</a>  <hr><img src="/icons/back.gif" alt="[PARENTDIR]"> <a href="/">Parent Directory</a>                             -
+<img src="/icons/folder.gif" alt="[DIR]"> <a href="Albums/">Albums/</a>                 01-Apr-2023 12:30    -
+<img src="/icons/sound2.gif" alt="[SND]"> <a href="02.flac">02.flac</a>                 02-Apr-2023 08:16:05  4718592
+<hr></pre>
+</body></html>
+"#;
+
+    fn summary(entries: &[RemoteEntry]) -> Vec<(&str, &str, Option<String>, Option<u64>)> {
+        entries
+            .iter()
+            .map(|e| {
+                (
+                    e.name.as_deref().unwrap(),
+                    e.r#type.as_deref().unwrap(),
+                    e.mtime.clone(),
+                    e.size,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn html_table() {
+        assert_eq!(
+            summary(&parse(TABLE)),
+            vec![
+                ("Albums", "directory", mtime("2023-04-01T12:30:00Z"), None),
+                (
+                    "01 Intro & Outro.flac",
+                    "file",
+                    mtime("2023-04-02T08:15:42Z"),
+                    Some(4718592)
+                ),
+                ("02.flac", "file", mtime("2023-04-02T08:16:00Z"), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn pre_block() {
+        assert_eq!(
+            summary(&parse(PRE)),
+            vec![
+                ("Albums", "directory", mtime("2023-04-01T12:30:00Z"), None),
+                (
+                    "02.flac",
+                    "file",
+                    mtime("2023-04-02T08:16:05Z"),
+                    Some(4718592)
+                ),
+            ]
+        );
+    }
+}
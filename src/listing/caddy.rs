@@ -0,0 +1,82 @@
+//! Caddy `file_server browse` listings, served as JSON on
+//! `Accept: application/json`.
+
+use super::{invalid_data, remote_entry};
+use crate::client::RemoteEntry;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::io;
+
+#[derive(Debug, Deserialize)]
+struct CaddyEntry {
+    name: String,
+    #[serde(default)]
+    size: u64,
+    mod_time: Option<String>,
+    #[serde(default)]
+    is_dir: bool,
+}
+
+impl From<CaddyEntry> for RemoteEntry {
+    fn from(entry: CaddyEntry) -> Self {
+        let mtime = entry
+            .mod_time
+            .and_then(|mtime| DateTime::parse_from_rfc3339(&mtime).ok())
+            .map(|mtime| mtime.with_timezone(&Utc));
+        let name = entry.name.trim_end_matches('/').to_string();
+        remote_entry(name, entry.is_dir, mtime, Some(entry.size))
+    }
+}
+
+pub fn parse(body: &str) -> io::Result<Vec<RemoteEntry>> {
+    let entries: Vec<CaddyEntry> = serde_json::from_str(body).map_err(invalid_data)?;
+    Ok(entries.into_iter().map(RemoteEntry::from).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listing::tests::mtime;
+
+    /// `curl -H 'Accept: application/json'` of Caddy 2.7 `file_server browse`.
+    const BROWSE: &str = r#"[{"name":"Albums/","size":4096,"url":"./Albums/","mod_time":"2023-04-01T14:30:00.123456789+02:00","mode":2147484141,"is_dir":true,"is_symlink":false},{"name":"01 Intro & Outro.flac","size":4718592,"url":"./01%20Intro%20&%20Outro.flac","mod_time":"2023-04-02T08:15:42Z","mode":420,"is_dir":false,"is_symlink":false},{"name":"latest","size":7,"url":"./latest/","mod_time":"2023-04-03T10:00:00Z","mode":134218239,"is_dir":true,"is_symlink":true}]"#;
+
+    #[test]
+    fn browse() {
+        let entries = parse(BROWSE).unwrap();
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|e| {
+                (
+                    e.name.as_deref().unwrap(),
+                    e.r#type.as_deref().unwrap(),
+                    e.mtime.clone(),
+                    e.size,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("Albums", "directory", mtime("2023-04-01T12:30:00Z"), None),
+                (
+                    "01 Intro & Outro.flac",
+                    "file",
+                    mtime("2023-04-02T08:15:42Z"),
+                    Some(4718592)
+                ),
+                ("latest", "directory", mtime("2023-04-03T10:00:00Z"), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_dir() {
+        assert_eq!(parse("[]").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn not_caddy() {
+        assert!(parse(r#"{"error":"not found"}"#).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+//! Fallback for any HTML index page: every `<a href>` pointing to a direct
+//! child becomes an entry, a trailing slash marks a directory. Sizes and
+//! modification times are unknown here and resolved later by `stat`.
+
+use super::{href_to_name, remote_entry};
+use crate::client::RemoteEntry;
+use regex::Regex;
+use std::collections::HashSet;
+
+pub fn parse(body: &str) -> Vec<RemoteEntry> {
+    let anchor = Regex::new(r#"(?is)<a\s[^>]*?href\s*=\s*["']?([^"'\s>]*)"#).unwrap();
+    let mut seen = HashSet::new();
+    anchor
+        .captures_iter(body)
+        .filter_map(|link| href_to_name(&link[1]))
+        .filter(|(name, _)| seen.insert(name.clone()))
+        .map(|(name, is_dir)| remote_entry(name, is_dir, None, None))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `python3 -m http.server` listing of `/music/`.
+    const PYTHON: &str = r#"<!DOCTYPE HTML>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>Directory listing for /music/</title>
+</head>
+<body>
+<h1>Directory listing for /music/</h1>
+<hr>
+<ul>
+<li><a href="Albums/">Albums/</a></li>
+<li><a href="01%20Intro%20%26%20Outro.flac">01 Intro &amp; Outro.flac</a></li>
+<li><a href="latest/">latest@/</a></li>
+</ul>
+<hr>
+</body>
+</html>
+"#;
+
+    /// lighttpd `mod_dirlisting` table with a parent link and sort headers.
+    const LIGHTTPD: &str = r##"<table summary="Directory Listing" cellpadding="0" cellspacing="0">
+<thead><tr><th class="n"><a href="?C=N&amp;O=D">Name</a></th><th class="m">Last Modified</th><th class="s">Size</th><th class="t">Type</th></tr></thead>
+<tbody>
+<tr class="d"><td class="n"><a href="../">..</a>/</td><td class="m">&nbsp;</td><td class="s">- &nbsp;</td><td class="t">Directory</td></tr>
+<tr><td class="n"><a href="./it%27s.mp3">it&#39;s.mp3</a></td><td class="m">2023-Apr-02 08:15:42</td><td class="s">4.5M</td><td class="t">audio/mpeg</td></tr>
+<tr><td class="n"><a href='cover.jpg'>cover.jpg</a></td><td class="m">2023-Apr-02 08:16:00</td><td class="s">12.1K</td><td class="t">image/jpeg</td></tr>
+<tr><td class="n"><a href=cover.jpg>cover.jpg</a></td></tr>
+</tbody>
+</table>
+<div class="foot"><a href="https://www.lighttpd.net/">lighttpd/1.4.69</a> <a href="/music/">home</a> <a href="#top">top</a></div>
+"##;
+
+    fn summary(entries: &[RemoteEntry]) -> Vec<(&str, &str)> {
+        entries
+            .iter()
+            .map(|e| (e.name.as_deref().unwrap(), e.r#type.as_deref().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn python() {
+        let entries = parse(PYTHON);
+        assert_eq!(
+            summary(&entries),
+            vec![
+                ("Albums", "directory"),
+                ("01 Intro & Outro.flac", "file"),
+                ("latest", "directory"),
+            ]
+        );
+        // Left to `stat`.
+        assert!(entries
+            .iter()
+            .all(|e| e.size.is_none() && e.mtime.is_none()));
+    }
+
+    #[test]
+    fn lighttpd() {
+        assert_eq!(
+            summary(&parse(LIGHTTPD)),
+            vec![("it's.mp3", "file"), ("cover.jpg", "file")]
+        );
+    }
+
+    #[test]
+    fn empty_dir() {
+        let page = r#"<html><body><h1>Directory listing for /empty/</h1><hr><ul>
+</ul><hr><a href="../">Up</a></body></html>"#;
+        assert_eq!(parse(page), vec![]);
+    }
+}
@@ -0,0 +1,241 @@
+//! Parsers turning server directory indexes into `RemoteEntry` values.
+
+mod apache;
+mod caddy;
+mod html;
+mod nginx;
+pub mod webdav;
+
+use crate::client::RemoteEntry;
+use chrono::{DateTime, Utc};
+use percent_encoding::percent_decode_str;
+use std::{io, str::FromStr};
+
+/// Shape of the directory index served by the remote side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ListingFormat {
+    /// Probe the server at startup and pick one of the below.
+    #[default]
+    Auto,
+    /// NGINX `autoindex_format json`.
+    Json,
+    /// NGINX `autoindex_format xml`.
+    Xml,
+    /// Apache `mod_autoindex` HTML page.
+    Apache,
+    /// WebDAV `PROPFIND` multistatus.
+    WebDav,
+    /// Caddy `file_server browse` JSON.
+    Caddy,
+    /// Links scraped from any HTML page.
+    Html,
+}
+
+impl FromStr for ListingFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(ListingFormat::Auto),
+            "json" => Ok(ListingFormat::Json),
+            "xml" => Ok(ListingFormat::Xml),
+            "apache" => Ok(ListingFormat::Apache),
+            "webdav" => Ok(ListingFormat::WebDav),
+            "caddy" => Ok(ListingFormat::Caddy),
+            "html" => Ok(ListingFormat::Html),
+            _ => Err(format!("Unknown listing format '{}'", s)),
+        }
+    }
+}
+
+/// Parse an index `body` of the directory at URL path `dir` according to `format`.
+pub fn parse(format: ListingFormat, body: &str, dir: &str) -> io::Result<Vec<RemoteEntry>> {
+    match format {
+        ListingFormat::Auto => Err(invalid_data("Listing format is not detected")),
+        ListingFormat::Json => nginx::parse_json(body),
+        ListingFormat::Xml => nginx::parse_xml(body),
+        ListingFormat::Apache => Ok(apache::parse(body)),
+        ListingFormat::WebDav => webdav::parse(body, dir),
+        ListingFormat::Caddy => caddy::parse(body),
+        ListingFormat::Html => Ok(html::parse(body)),
+    }
+}
+
+/// Guess the format of an index page from its `Content-Type` and `body`.
+///
+/// An empty JSON array may come from NGINX as well as from Caddy. It is
+/// taken for Caddy, asking for JSON works with both, but `ambiguous` tells
+/// the guess is not to be kept.
+pub fn detect(content_type: &str, body: &str) -> Option<ListingFormat> {
+    let content_type = content_type.to_lowercase();
+    let head = body.trim_start();
+    if content_type.contains("json") || head.starts_with('[') {
+        let entries: Vec<serde_json::Value> = serde_json::from_str(body).ok()?;
+        return match entries.first() {
+            Some(entry) if entry.get("is_dir").is_some() || entry.get("mod_time").is_some() => {
+                Some(ListingFormat::Caddy)
+            }
+            Some(_) => Some(ListingFormat::Json),
+            None => Some(ListingFormat::Caddy),
+        };
+    }
+    if content_type.contains("xml") || head.starts_with("<?xml") {
+        return match roxmltree::Document::parse(body) {
+            Ok(doc) if doc.root_element().tag_name().name() == "list" => Some(ListingFormat::Xml),
+            _ => None,
+        };
+    }
+    if content_type.contains("html") || head.starts_with('<') {
+        // Sorting links are what mod_autoindex puts into the column headers.
+        let apache = body.contains("?C=N;O=") || body.contains("?C=M;O=");
+        return Some(if apache {
+            ListingFormat::Apache
+        } else {
+            ListingFormat::Html
+        });
+    }
+    None
+}
+
+/// The index page `body` can't tell NGINX and Caddy JSON apart.
+pub fn ambiguous(body: &str) -> bool {
+    serde_json::from_str::<Vec<serde_json::Value>>(body).is_ok_and(|entries| entries.is_empty())
+}
+
+pub fn invalid_data<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Build an entry the same way NGINX would have described it.
+pub fn remote_entry(
+    name: String,
+    is_dir: bool,
+    mtime: Option<DateTime<Utc>>,
+    size: Option<u64>,
+) -> RemoteEntry {
+    RemoteEntry {
+        name: Some(name),
+        r#type: Some(String::from(if is_dir { "directory" } else { "file" })),
+        mtime: mtime.map(|mtime| mtime.to_rfc2822()),
+        size: if is_dir { None } else { size },
+        etag: None,
+    }
+}
+
+/// Turn an `href` found on an index page into an entry name.
+///
+/// Returns `None` for links which do not point to a direct child: sorting
+/// queries, the parent directory, absolute and external URLs.
+pub fn href_to_name(href: &str) -> Option<(String, bool)> {
+    let href = unescape_html(href);
+    if href.is_empty()
+        || href.starts_with('?')
+        || href.starts_with('#')
+        || href.starts_with('/')
+        || href.starts_with("..")
+        || href.contains("://")
+        || href.starts_with("mailto:")
+    {
+        return None;
+    }
+    let href = href.split(['?', '#']).next().unwrap_or_default();
+    let href = href.strip_prefix("./").unwrap_or(href);
+    let is_dir = href.ends_with('/');
+    let href = href.trim_end_matches('/');
+    if href.is_empty() || href.contains('/') {
+        return None;
+    }
+    let name = percent_decode_str(href).decode_utf8_lossy().into_owned();
+    Some((name, is_dir))
+}
+
+/// Decode the handful of entities index generators put into names.
+pub fn unescape_html(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// `mtime` of an entry modified at RFC 3339 `time`, as parsers put it.
+    pub fn mtime(time: &str) -> Option<String> {
+        let time = DateTime::parse_from_rfc3339(time).unwrap();
+        Some(time.with_timezone(&Utc).to_rfc2822())
+    }
+
+    #[test]
+    fn detect_formats() {
+        let nginx =
+            r#"[{ "name":"Albums", "type":"directory", "mtime":"Sat, 01 Apr 2023 12:30:00 GMT" }]"#;
+        let caddy = r#"[{"name":"Albums/","size":4096,"url":"./Albums/","mod_time":"2023-04-01T12:30:00Z","mode":2147484141,"is_dir":true,"is_symlink":false}]"#;
+        let xml = r#"<?xml version="1.0"?>
+<list><directory mtime="2023-04-01T12:30:00Z">Albums</directory></list>"#;
+        let apache = r#"<html><body><pre><a href="?C=N;O=D">Name</a></pre></body></html>"#;
+        let python = r#"<html><body><ul><li><a href="Albums/">Albums/</a></li></ul></body></html>"#;
+        let cases = [
+            ("application/json", nginx, Some(ListingFormat::Json)),
+            ("application/json", caddy, Some(ListingFormat::Caddy)),
+            // Served as a static file without a JSON content type.
+            ("application/octet-stream", nginx, Some(ListingFormat::Json)),
+            ("text/xml", xml, Some(ListingFormat::Xml)),
+            (
+                "text/html;charset=UTF-8",
+                apache,
+                Some(ListingFormat::Apache),
+            ),
+            (
+                "text/html; charset=utf-8",
+                python,
+                Some(ListingFormat::Html),
+            ),
+            ("application/xml", "<rss></rss>", None),
+            ("text/plain", "Forbidden", None),
+        ];
+        for (content_type, body, format) in cases.iter() {
+            assert_eq!(detect(content_type, body), *format, "{}", body);
+            assert!(!ambiguous(body));
+        }
+    }
+
+    #[test]
+    fn detect_empty_json() {
+        assert_eq!(detect("application/json", "[]"), Some(ListingFormat::Caddy));
+        assert!(ambiguous("[]"));
+        assert!(ambiguous(" [ ]\n"));
+    }
+
+    #[test]
+    fn hrefs() {
+        let cases = [
+            ("Albums/", Some(("Albums", true))),
+            (
+                "./01%20Intro%20%26%20Outro.flac",
+                Some(("01 Intro & Outro.flac", false)),
+            ),
+            ("it&#39;s.mp3", Some(("it's.mp3", false))),
+            ("song.mp3?download=1", Some(("song.mp3", false))),
+            ("../", None),
+            ("..", None),
+            ("/music/", None),
+            ("?C=M;O=A", None),
+            ("#top", None),
+            ("https://nginx.org/", None),
+            ("mailto:root@localhost", None),
+            ("Albums/01.flac", None),
+        ];
+        for (href, name) in cases.iter() {
+            let found = href_to_name(href);
+            let found = found
+                .as_ref()
+                .map(|(name, is_dir)| (name.as_str(), *is_dir));
+            assert_eq!(found, *name, "{}", href);
+        }
+    }
+}
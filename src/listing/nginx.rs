@@ -0,0 +1,94 @@
+//! NGINX `autoindex` listings in the `json` and `xml` formats.
+
+use super::{invalid_data, remote_entry};
+use crate::client::RemoteEntry;
+use chrono::{DateTime, Utc};
+use std::io;
+
+pub fn parse_json(body: &str) -> io::Result<Vec<RemoteEntry>> {
+    serde_json::from_str(body).map_err(invalid_data)
+}
+
+/// `<list>` of `<directory>` and `<file>` elements holding the name as text,
+/// with an RFC 3339 `mtime` and, for files, a `size` attribute.
+pub fn parse_xml(body: &str) -> io::Result<Vec<RemoteEntry>> {
+    let doc = roxmltree::Document::parse(body).map_err(invalid_data)?;
+    let entries = doc
+        .root_element()
+        .children()
+        .filter(|node| node.is_element())
+        .filter_map(|node| {
+            let is_dir = match node.tag_name().name() {
+                "directory" => true,
+                "file" => false,
+                _ => return None,
+            };
+            let name = node.text()?.to_string();
+            let mtime = node
+                .attribute("mtime")
+                .and_then(|mtime| DateTime::parse_from_rfc3339(mtime).ok())
+                .map(|mtime| mtime.with_timezone(&Utc));
+            let size = node.attribute("size").and_then(|size| size.parse().ok());
+            Some(remote_entry(name, is_dir, mtime, size))
+        })
+        .collect();
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// `autoindex_format json` of NGINX 1.24.
+    const JSON: &str = r#"[
+{ "name":"Albums", "type":"directory", "mtime":"Sat, 01 Apr 2023 12:30:00 GMT" },
+{ "name":"01 Intro & Outro.flac", "type":"file", "mtime":"Sun, 02 Apr 2023 08:15:42 GMT", "size":4718592 },
+{ "name":"latest", "type":"other", "mtime":"Mon, 03 Apr 2023 10:00:00 GMT" }
+]
+"#;
+
+    /// `autoindex_format xml` of NGINX 1.24.
+    const XML: &str = r#"<?xml version="1.0"?>
+<list>
+<directory mtime="2023-04-01T12:30:00Z">Albums</directory>
+<file mtime="2023-04-02T08:15:42Z" size="4718592">01 Intro &amp; Outro.flac</file>
+<other mtime="2023-04-03T10:00:00Z">latest</other>
+</list>
+"#;
+
+    #[test]
+    fn json() {
+        let entries = parse_json(JSON).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].name.as_deref(), Some("01 Intro & Outro.flac"));
+        assert_eq!(entries[1].size, Some(4718592));
+        assert_eq!(entries[2].r#type.as_deref(), Some("other"));
+        assert!(parse_json("[]").unwrap().is_empty());
+    }
+
+    #[test]
+    fn xml() {
+        let entries = parse_xml(XML).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                remote_entry(
+                    String::from("Albums"),
+                    true,
+                    Utc.with_ymd_and_hms(2023, 4, 1, 12, 30, 0).single(),
+                    None
+                ),
+                remote_entry(
+                    String::from("01 Intro & Outro.flac"),
+                    false,
+                    Utc.with_ymd_and_hms(2023, 4, 2, 8, 15, 42).single(),
+                    Some(4718592)
+                ),
+            ]
+        );
+        assert!(parse_xml("<?xml version=\"1.0\"?>\n<list>\n</list>\n")
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,220 @@
+//! WebDAV `PROPFIND` multistatus responses.
+
+use super::{invalid_data, remote_entry};
+use crate::client::RemoteEntry;
+use chrono::{DateTime, Utc};
+use percent_encoding::percent_decode_str;
+use roxmltree::Node;
+use std::io;
+
+const DAV: &str = "DAV:";
+
+/// Body of the `Depth: 1` request asking for just what `MemFS` needs.
+pub const PROPFIND: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<propfind xmlns="DAV:">
+  <prop>
+    <resourcetype/>
+    <getcontentlength/>
+    <getlastmodified/>
+    <getetag/>
+  </prop>
+</propfind>
+"#;
+
+/// Parse a multistatus answer for the collection at URL path `dir`.
+///
+/// The collection itself is reported alongside its members and is skipped.
+pub fn parse(body: &str, dir: &str) -> io::Result<Vec<RemoteEntry>> {
+    let doc = roxmltree::Document::parse(body).map_err(invalid_data)?;
+    let dir = normalize(dir);
+    let entries = doc
+        .root_element()
+        .children()
+        .filter(|node| is_dav(node, "response"))
+        .filter_map(|response| {
+            let href = normalize(&child_text(response, "href")?);
+            if href == dir {
+                return None;
+            }
+            let name = href.rsplit('/').next()?.to_string();
+            let prop = |name| response.descendants().find(|node| is_dav(node, name));
+            let is_dir = prop("resourcetype")
+                .map(|node| node.children().any(|child| is_dav(&child, "collection")))
+                .unwrap_or(false);
+            let mtime = prop("getlastmodified")
+                .and_then(|node| node.text())
+                .and_then(|mtime| DateTime::parse_from_rfc2822(mtime.trim()).ok())
+                .map(|mtime| mtime.with_timezone(&Utc));
+            let size = prop("getcontentlength")
+                .and_then(|node| node.text())
+                .and_then(|size| size.trim().parse().ok());
+            let etag = prop("getetag")
+                .and_then(|node| node.text())
+                .map(|etag| etag.trim().to_string());
+            let mut entry = remote_entry(name, is_dir, mtime, size);
+            entry.etag = etag;
+            Some(entry)
+        })
+        .collect();
+    Ok(entries)
+}
+
+fn is_dav(node: &Node, name: &str) -> bool {
+    node.is_element() && node.tag_name().name() == name && node.tag_name().namespace() == Some(DAV)
+}
+
+fn child_text(node: Node, name: &str) -> Option<String> {
+    node.children()
+        .find(|child| is_dav(child, name))
+        .and_then(|child| child.text())
+        .map(|text| text.trim().to_string())
+}
+
+/// Reduce an `href`, absolute URL or path, to a decoded path without the
+/// trailing slash so members and the collection can be compared.
+fn normalize(href: &str) -> String {
+    let path = match href.find("://") {
+        Some(scheme) => {
+            let rest = &href[scheme + 3..];
+            rest.find('/').map_or("", |start| &rest[start..])
+        }
+        None => href,
+    };
+    let path = percent_decode_str(path).decode_utf8_lossy();
+    let mut normalized = String::with_capacity(path.len());
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listing::tests::mtime;
+
+    /// Apache `mod_dav` answer for `/dav/music/`.
+    const MULTISTATUS: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:" xmlns:ns0="DAV:">
+<D:response xmlns:lp1="DAV:" xmlns:lp2="http://apache.org/dav/props/">
+<D:href>/dav/music/</D:href>
+<D:propstat>
+<D:prop>
+<lp1:resourcetype><D:collection/></lp1:resourcetype>
+<lp1:getlastmodified>Sat, 01 Apr 2023 12:30:00 GMT</lp1:getlastmodified>
+<lp1:getetag>"1000-5f84a1d1c7a2b"</lp1:getetag>
+</D:prop>
+<D:status>HTTP/1.1 200 OK</D:status>
+</D:propstat>
+</D:response>
+<D:response xmlns:lp1="DAV:" xmlns:lp2="http://apache.org/dav/props/">
+<D:href>/dav/music/Best%20Of%20%26%20More/</D:href>
+<D:propstat>
+<D:prop>
+<lp1:resourcetype><D:collection/></lp1:resourcetype>
+<lp1:getlastmodified>Sat, 01 Apr 2023 12:31:00 GMT</lp1:getlastmodified>
+<lp1:getetag>"1000-5f84a1d1c7a2c"</lp1:getetag>
+</D:prop>
+<D:status>HTTP/1.1 200 OK</D:status>
+</D:propstat>
+</D:response>
+<D:response xmlns:lp1="DAV:" xmlns:lp2="http://apache.org/dav/props/">
+<D:href>/dav/music/01%20%D0%98%D0%BD%D1%82%D1%80%D0%BE.flac</D:href>
+<D:propstat>
+<D:prop>
+<lp1:resourcetype/>
+<lp1:getcontentlength>4718592</lp1:getcontentlength>
+<lp1:getlastmodified>Sun, 02 Apr 2023 08:15:42 GMT</lp1:getlastmodified>
+<lp1:getetag>"480000-5f8a2c1e0b380"</lp1:getetag>
+</D:prop>
+<D:status>HTTP/1.1 200 OK</D:status>
+</D:propstat>
+</D:response>
+</D:multistatus>
+"#;
+
+    /// Nextcloud answer with absolute hrefs and a property it doesn't have.
+    const ABSOLUTE: &str = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:s="http://sabredav.org/ns">
+ <d:response>
+  <d:href>https://cloud.example.com/remote.php/dav/files/user/Music/</d:href>
+  <d:propstat>
+   <d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop>
+   <d:status>HTTP/1.1 200 OK</d:status>
+  </d:propstat>
+ </d:response>
+ <d:response>
+  <d:href>https://cloud.example.com/remote.php/dav/files/user/Music/02.flac</d:href>
+  <d:propstat>
+   <d:prop>
+    <d:resourcetype/>
+    <d:getcontentlength>1024</d:getcontentlength>
+    <d:getlastmodified>Sun, 02 Apr 2023 08:16:00 GMT</d:getlastmodified>
+   </d:prop>
+   <d:status>HTTP/1.1 200 OK</d:status>
+  </d:propstat>
+  <d:propstat>
+   <d:prop><d:getetag/></d:prop>
+   <d:status>HTTP/1.1 404 Not Found</d:status>
+  </d:propstat>
+ </d:response>
+</d:multistatus>
+"#;
+
+    const EMPTY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:">
+<D:response>
+<D:href>/dav/empty/</D:href>
+<D:propstat>
+<D:prop><D:resourcetype><D:collection/></D:resourcetype></D:prop>
+<D:status>HTTP/1.1 200 OK</D:status>
+</D:propstat>
+</D:response>
+</D:multistatus>
+"#;
+
+    #[test]
+    fn mod_dav() {
+        let entries = parse(MULTISTATUS, "/dav/music/").unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                RemoteEntry {
+                    name: Some(String::from("Best Of & More")),
+                    r#type: Some(String::from("directory")),
+                    mtime: mtime("2023-04-01T12:31:00Z"),
+                    size: None,
+                    etag: Some(String::from("\"1000-5f84a1d1c7a2c\"")),
+                },
+                RemoteEntry {
+                    name: Some(String::from("01 Интро.flac")),
+                    r#type: Some(String::from("file")),
+                    mtime: mtime("2023-04-02T08:15:42Z"),
+                    size: Some(4718592),
+                    etag: Some(String::from("\"480000-5f8a2c1e0b380\"")),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn absolute_hrefs() {
+        // The collection is told apart however the request path is spelled.
+        let entries = parse(ABSOLUTE, "/remote.php/dav/files/user/Music").unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name.as_deref(), Some("02.flac"));
+        assert_eq!(entries[0].size, Some(1024));
+        assert_eq!(entries[0].etag, None);
+    }
+
+    #[test]
+    fn empty_collection() {
+        assert_eq!(parse(EMPTY, "/dav/empty/").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn not_xml() {
+        assert!(parse("<html>", "/").is_err());
+    }
+}
@@ -0,0 +1,101 @@
+//! Local directory backend, `server: file:///srv/music`.
+//!
+//! Serves a tree from the local disk through the same listing and read path
+//! the HTTP backends use, handy to reproduce filesystem bugs without a web
+//! server and to compare furumi against the real tree.
+
+use crate::client::{file_name, ListingBackend, RemoteEntry};
+use crate::listing::remote_entry;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::{
+    fs::{self, File, Metadata},
+    io,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+use tokio::task;
+
+#[derive(Debug, Clone)]
+pub struct Local {
+    root: PathBuf,
+}
+
+impl Local {
+    pub fn new(server: &str) -> Self {
+        let root = PathBuf::from(server.trim_start_matches("file://"));
+        info!("Serving local directory {}", root.display());
+        Self { root }
+    }
+
+    /// Location of the remote `path` on the local disk.
+    fn local_path(&self, path: &Path) -> PathBuf {
+        self.root.join(path.strip_prefix("/").unwrap_or(path))
+    }
+}
+
+fn entry(name: String, meta: &Metadata) -> RemoteEntry {
+    let mtime = meta.modified().ok().map(DateTime::<Utc>::from);
+    remote_entry(name, meta.is_dir(), mtime, Some(meta.len()))
+}
+
+pub fn blocking_error(e: task::JoinError) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+#[async_trait]
+impl ListingBackend for Local {
+    async fn list(&self, path: PathBuf) -> io::Result<Vec<RemoteEntry>> {
+        let dir = self.local_path(&path);
+        debug!("Listing local directory {}", dir.display());
+        task::spawn_blocking(move || {
+            let mut entries = Vec::new();
+            for child in fs::read_dir(&dir)? {
+                let child = child?;
+                let meta = match fs::metadata(child.path()) {
+                    Ok(meta) => meta,
+                    Err(e) => {
+                        warn!("Skipping {}. {}", child.path().display(), e);
+                        continue;
+                    }
+                };
+                if meta.is_dir() || meta.is_file() {
+                    entries.push(entry(
+                        child.file_name().to_string_lossy().into_owned(),
+                        &meta,
+                    ));
+                }
+            }
+            Ok(entries)
+        })
+        .await
+        .map_err(blocking_error)?
+    }
+
+    async fn read(&self, path: PathBuf, size: usize, offset: usize) -> io::Result<Vec<u8>> {
+        let file = self.local_path(&path);
+        task::spawn_blocking(move || {
+            let file = File::open(file)?;
+            let mut buf = vec![0; size];
+            let mut len = 0;
+            while len < size {
+                match file.read_at(&mut buf[len..], (offset + len) as u64)? {
+                    0 => break,
+                    n => len += n,
+                }
+            }
+            buf.truncate(len);
+            Ok(buf)
+        })
+        .await
+        .map_err(blocking_error)?
+    }
+
+    async fn stat(&self, path: PathBuf) -> io::Result<RemoteEntry> {
+        let file = self.local_path(&path);
+        let name = file_name(&path).unwrap_or_default();
+        task::spawn_blocking(move || Ok(entry(name, &fs::metadata(file)?)))
+            .await
+            .map_err(blocking_error)?
+    }
+}
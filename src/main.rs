@@ -4,15 +4,36 @@ extern crate log;
 use env_logger::Env;
 use std::process;
 
+mod archive;
+mod cache;
 mod config;
+mod crypt;
 mod filesystem;
 mod client;
+mod index;
+mod listing;
+mod local;
+mod manifest;
+mod memcache;
+mod mounts;
+mod s3;
+mod seekable;
+mod union;
 use itertools::Itertools;
 
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
     env_logger::from_env(Env::default().default_filter_or("info")).init();
-    let cfg = config::read();
+    let cfg = match config::read() {
+        config::Command::Mount(cfg) => cfg,
+        config::Command::Index(args) => {
+            if let Err(e) = index::run(&args) {
+                error!("Can't index {}. {}", args.root.display(), e);
+                process::exit(0x0007);
+            }
+            return Ok(());
+        }
+    };
 
     let mountpoint: PathBuf = PathBuf::from(&cfg.mountpoint);
     if !mountpoint.is_dir() {
@@ -27,12 +48,11 @@ async fn main() -> Result<(), std::io::Error> {
     ].iter().join(",");
 
     let memfs = filesystem::MemFS::new(&cfg);
-    match memfs.fetch_remote(PathBuf::from("/"), 1).await {
-        Err(e) => {
+    if !memfs.load_manifest().await {
+        if let Err(e) = memfs.fetch_remote(PathBuf::from("/"), 1).await {
             error!("Connection failed. Check server address and credentials {}", e);
             process::exit(0x0005);
         }
-        _ => {}
     }
 
     polyfuse_tokio::mount(memfs, mountpoint, &[
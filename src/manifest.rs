@@ -0,0 +1,103 @@
+//! Index manifest describing a whole tree in one file.
+//!
+//! A server may publish `/.furumi-index.json`, optionally gzipped, listing
+//! every file and directory below the root. Loading it once replaces
+//! crawling the tree directory by directory.
+
+use crate::client::{ListingBackend, RemoteEntry};
+use crate::listing::invalid_data;
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use std::{io, io::Read, path::PathBuf};
+
+/// Names the manifest is looked up by, in order of preference.
+pub const MANIFEST_NAMES: [&str; 2] = [".furumi-index.json.gz", ".furumi-index.json"];
+
+pub const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Manifest {
+    pub version: u32,
+    /// RFC 2822 time the manifest was generated at.
+    pub generated: Option<String>,
+    pub entries: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ManifestEntry {
+    /// Path relative to the root of the tree.
+    pub path: String,
+    /// Same fields NGINX puts into its JSON listing, `name` aside.
+    #[serde(flatten)]
+    pub entry: RemoteEntry,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+impl Manifest {
+    /// Download and decode the manifest from the root of `backend`.
+    pub async fn fetch(backend: &dyn ListingBackend) -> io::Result<Manifest> {
+        let mut last_error = io::Error::from(io::ErrorKind::NotFound);
+        for name in MANIFEST_NAMES.iter() {
+            let path = PathBuf::from("/").join(name);
+            match Self::fetch_file(backend, path).await {
+                Ok(data) => {
+                    let manifest: Manifest = if name.ends_with(".gz") {
+                        let mut json = Vec::new();
+                        GzDecoder::new(data.as_slice()).read_to_end(&mut json)?;
+                        serde_json::from_slice(&json)
+                    } else {
+                        serde_json::from_slice(&data)
+                    }
+                    .map_err(invalid_data)?;
+                    if manifest.version != MANIFEST_VERSION {
+                        return Err(invalid_data(format!(
+                            "Unsupported manifest version {}",
+                            manifest.version
+                        )));
+                    }
+                    return Ok(manifest);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn fetch_file(backend: &dyn ListingBackend, path: PathBuf) -> io::Result<Vec<u8>> {
+        let size = backend.stat(path.clone()).await?.size.unwrap_or(0) as usize;
+        if size == 0 {
+            return Err(invalid_data(format!("Empty manifest {}", path.display())));
+        }
+        debug!(
+            "Fetching index manifest {} ({} bytes)",
+            path.display(),
+            size
+        );
+        backend.read(path, size, 0).await
+    }
+
+    /// Whether the manifest was generated more than `max_age` seconds ago.
+    pub fn is_stale(&self, max_age: Option<u64>) -> bool {
+        let max_age = match max_age {
+            Some(max_age) => max_age as i64,
+            None => return false,
+        };
+        match self
+            .generated
+            .as_ref()
+            .and_then(|generated| DateTime::parse_from_rfc2822(generated).ok())
+        {
+            Some(generated) => (Utc::now() - generated.with_timezone(&Utc)).num_seconds() > max_age,
+            None => true,
+        }
+    }
+
+    /// Entries ordered so that every directory comes before its content.
+    pub fn sorted_entries(&self) -> Vec<&ManifestEntry> {
+        let mut entries: Vec<&ManifestEntry> = self.entries.iter().collect();
+        entries.sort_by_key(|m_entry| (m_entry.path.matches('/').count(), &m_entry.path));
+        entries
+    }
+}
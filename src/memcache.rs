@@ -0,0 +1,88 @@
+//! Recently read blocks of files kept in memory, `memory_cache` in the
+//! config.
+//!
+//! Players read headers and seek tables over and over, those reads are
+//! served from here instead of the backend. Blocks of all files share one
+//! memory budget, the least recently used ones go first.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+/// Size of the blocks kept, reads are widened to whole blocks.
+pub const BLOCK: u64 = 128 * 1024;
+
+type Ino = u64;
+/// Blocks of a file by index, along with their last use.
+type Blocks = HashMap<u64, (Arc<Vec<u8>>, u64)>;
+
+#[derive(Debug, Default)]
+pub struct MemCache {
+    budget: u64,
+    used: u64,
+    tick: u64,
+    files: HashMap<Ino, Blocks>,
+    /// File and block index by last use.
+    order: BTreeMap<u64, (Ino, u64)>,
+}
+
+impl MemCache {
+    /// Cache of at most `budget` bytes, nothing is kept with zero.
+    pub fn new(budget: u64) -> Self {
+        Self {
+            budget,
+            ..Self::default()
+        }
+    }
+
+    pub fn get(&mut self, ino: Ino, index: u64) -> Option<Arc<Vec<u8>>> {
+        let (block, used) = self.files.get_mut(&ino)?.get_mut(&index)?;
+        self.tick += 1;
+        self.order.remove(used);
+        *used = self.tick;
+        self.order.insert(self.tick, (ino, index));
+        Some(block.clone())
+    }
+
+    pub fn insert(&mut self, ino: Ino, index: u64, block: Arc<Vec<u8>>) {
+        let size = block.len() as u64;
+        if size > self.budget {
+            return;
+        }
+        self.tick += 1;
+        let blocks = self.files.entry(ino).or_default();
+        if let Some((old, used)) = blocks.insert(index, (block, self.tick)) {
+            self.used -= old.len() as u64;
+            self.order.remove(&used);
+        }
+        self.used += size;
+        self.order.insert(self.tick, (ino, index));
+        while self.used > self.budget {
+            let (_, (ino, index)) = match self.order.pop_first() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            self.remove(ino, index);
+        }
+    }
+
+    /// Drop all blocks of `ino`, e.g. when the file changed.
+    pub fn forget(&mut self, ino: Ino) {
+        for (block, used) in self.files.remove(&ino).unwrap_or_default().into_values() {
+            self.used -= block.len() as u64;
+            self.order.remove(&used);
+        }
+    }
+
+    fn remove(&mut self, ino: Ino, index: u64) {
+        if let Some(blocks) = self.files.get_mut(&ino) {
+            if let Some((block, _)) = blocks.remove(&index) {
+                self.used -= block.len() as u64;
+            }
+            if blocks.is_empty() {
+                self.files.remove(&ino);
+            }
+        }
+    }
+}
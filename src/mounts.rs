@@ -0,0 +1,136 @@
+//! Mount table, top level paths of the mount served by different servers.
+//!
+//! Directories leading to the mount paths are synthetic, everything below a
+//! mount path is handed to its backend with the mount path stripped.
+
+use crate::client::{Changed, Listing, ListingBackend, RemoteEntry, Validators};
+use crate::listing::remote_entry;
+use async_trait::async_trait;
+use std::{
+    collections::HashSet,
+    io,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+#[derive(Debug)]
+pub struct MountTable {
+    /// Mount paths with their backends, the deepest paths first.
+    mounts: Vec<(PathBuf, Arc<dyn ListingBackend>)>,
+}
+
+impl MountTable {
+    pub fn new(mut mounts: Vec<(PathBuf, Arc<dyn ListingBackend>)>) -> Self {
+        mounts.sort_by_key(|(path, _)| std::cmp::Reverse(path.components().count()));
+        Self { mounts }
+    }
+
+    /// Backend serving `path` and the path on its side.
+    fn resolve(&self, path: &Path) -> Option<(&Arc<dyn ListingBackend>, PathBuf)> {
+        self.mounts.iter().find_map(|(mount, backend)| {
+            let rest = path.strip_prefix(mount).ok()?;
+            Some((backend, Path::new("/").join(rest)))
+        })
+    }
+
+    /// Names of synthetic directories right under `path` leading to mounts.
+    fn synthetic(&self, path: &Path) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .mounts
+            .iter()
+            .filter_map(|(mount, _)| match mount.strip_prefix(path).ok()?.components().next() {
+                Some(Component::Normal(name)) => Some(name.to_string_lossy().into_owned()),
+                _ => None,
+            })
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+/// Put the `synthetic` directories into `entries`. Mount paths shadow
+/// whatever the parent server has there.
+fn add_synthetic(entries: &mut Vec<RemoteEntry>, synthetic: &[String]) {
+    let shadowed: HashSet<&String> = synthetic.iter().collect();
+    entries.retain(|entry| entry.name.as_ref().is_none_or(|name| !shadowed.contains(name)));
+    entries.extend(
+        synthetic
+            .iter()
+            .map(|name| remote_entry(name.clone(), true, None, None)),
+    );
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("'{}' is not in the mount table", path.display()),
+    )
+}
+
+#[async_trait]
+impl ListingBackend for MountTable {
+    async fn list(&self, path: PathBuf) -> io::Result<Vec<RemoteEntry>> {
+        let synthetic = self.synthetic(&path);
+        let mut entries = match self.resolve(&path) {
+            Some((backend, remote)) => backend.list(remote).await?,
+            None if synthetic.is_empty() => return Err(not_found(&path)),
+            None => Vec::new(),
+        };
+        add_synthetic(&mut entries, &synthetic);
+        Ok(entries)
+    }
+
+    async fn list_changed(&self, path: PathBuf, validators: &Validators) -> io::Result<Changed> {
+        let synthetic = self.synthetic(&path);
+        let mut listing = match self.resolve(&path) {
+            Some((backend, remote)) => backend.list_changed(remote, validators).await?,
+            None if synthetic.is_empty() => return Err(not_found(&path)),
+            None => Some(Listing::default()),
+        };
+        if let Some(listing) = &mut listing {
+            add_synthetic(&mut listing.entries, &synthetic);
+        }
+        Ok(listing)
+    }
+
+    async fn read(&self, path: PathBuf, size: usize, offset: usize) -> io::Result<Vec<u8>> {
+        match self.resolve(&path) {
+            Some((backend, remote)) => backend.read(remote, size, offset).await,
+            None => Err(not_found(&path)),
+        }
+    }
+
+    async fn stat(&self, path: PathBuf) -> io::Result<RemoteEntry> {
+        match self.resolve(&path) {
+            Some((backend, remote)) => backend.stat(remote).await,
+            None => Err(not_found(&path)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::tests::Server;
+    use std::sync::atomic::Ordering;
+
+    #[tokio::test]
+    async fn list_changed_forwarded() {
+        let server = Arc::new(Server::new(&[("a", b"a")]));
+        let table = MountTable::new(vec![(PathBuf::from("/music/rock"), server.clone())]);
+        let validators = Validators::default();
+
+        let listing = table.list_changed("/music".into(), &validators).await;
+        let entries = listing.unwrap().unwrap().entries;
+        assert_eq!(entries[0].name.as_deref(), Some("rock"));
+        assert_eq!(entries.len(), 1);
+
+        let listing = table.list_changed("/music/rock".into(), &validators).await;
+        assert_eq!(listing.unwrap().unwrap().entries.len(), 1);
+
+        server.unchanged.store(true, Ordering::SeqCst);
+        let listing = table.list_changed("/music/rock".into(), &validators).await;
+        assert!(listing.unwrap().is_none());
+    }
+}
@@ -0,0 +1,377 @@
+//! Transparent decompression of seekable zstd files.
+//!
+//! `foo.zst` made of independent frames followed by a seek table is shown as
+//! `foo` with its decompressed size. Reads fetch and decompress only the
+//! frames covering the requested range. Plain zstd files without a seek
+//! table are left as they are.
+
+use crate::client::{Changed, ListingBackend, RemoteEntry, Validators};
+use crate::listing::invalid_data;
+use async_trait::async_trait;
+use futures::future::join_all;
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+const SKIPPABLE_MAGIC: u32 = 0x184d_2a5e;
+const SEEKABLE_MAGIC: u32 = 0x8f92_eab1;
+const FOOTER_SIZE: usize = 9;
+const SKIPPABLE_HEADER_SIZE: usize = 8;
+
+fn u32_at(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    /// Offset of the compressed frame in the file.
+    offset: u64,
+    compressed: u64,
+    /// Offset of the frame content in the decompressed stream.
+    start: u64,
+    size: u64,
+}
+
+#[derive(Debug)]
+struct SeekTable {
+    frames: Vec<Frame>,
+    size: u64,
+    /// Last decompressed frame, sequential reads mostly hit it again.
+    last: Mutex<Option<(usize, Arc<Vec<u8>>)>>,
+}
+
+impl SeekTable {
+    /// Read the seek table at the end of the file at `path` of `size` bytes,
+    /// `None` when there is none.
+    async fn fetch(
+        backend: &dyn ListingBackend,
+        path: &Path,
+        size: u64,
+    ) -> io::Result<Option<Self>> {
+        if size < FOOTER_SIZE as u64 {
+            return Ok(None);
+        }
+        let footer = backend
+            .read(
+                path.to_path_buf(),
+                FOOTER_SIZE,
+                (size - FOOTER_SIZE as u64) as usize,
+            )
+            .await?;
+        if footer.len() < FOOTER_SIZE || u32_at(&footer, 5) != SEEKABLE_MAGIC {
+            return Ok(None);
+        }
+        let count = u32_at(&footer, 0) as usize;
+        let entry_size = if footer[4] & 0x80 != 0 { 12 } else { 8 };
+        let frame_size = count * entry_size + FOOTER_SIZE;
+        let table_size = (SKIPPABLE_HEADER_SIZE + frame_size) as u64;
+        if table_size > size {
+            return Ok(None);
+        }
+        let table = backend
+            .read(
+                path.to_path_buf(),
+                table_size as usize,
+                (size - table_size) as usize,
+            )
+            .await?;
+        if table.len() < table_size as usize
+            || u32_at(&table, 0) != SKIPPABLE_MAGIC
+            || u32_at(&table, 4) as usize != frame_size
+        {
+            return Ok(None);
+        }
+
+        let mut frames = Vec::with_capacity(count);
+        let (mut offset, mut start) = (0, 0);
+        for entry in table[SKIPPABLE_HEADER_SIZE..]
+            .chunks_exact(entry_size)
+            .take(count)
+        {
+            let frame = Frame {
+                offset,
+                compressed: u32_at(entry, 0) as u64,
+                start,
+                size: u32_at(entry, 4) as u64,
+            };
+            offset += frame.compressed;
+            start += frame.size;
+            frames.push(frame);
+        }
+        debug!(
+            "'{}' has {} zstd frames, {} bytes decompressed",
+            path.display(),
+            frames.len(),
+            start
+        );
+        Ok(Some(Self {
+            frames,
+            size: start,
+            last: Mutex::new(None),
+        }))
+    }
+
+    /// Decompressed content of the frames `first..=last`, fetched with one
+    /// request unless it's the cached one.
+    async fn frames(
+        &self,
+        backend: &dyn ListingBackend,
+        path: &Path,
+        first: usize,
+        last: usize,
+    ) -> io::Result<Vec<Arc<Vec<u8>>>> {
+        if first == last {
+            if let Some((cached, content)) = &*self.last.lock().unwrap() {
+                if *cached == first {
+                    return Ok(vec![content.clone()]);
+                }
+            }
+        }
+        let (from, to) = (&self.frames[first], &self.frames[last]);
+        let length = to.offset + to.compressed - from.offset;
+        let data = backend
+            .read(path.to_path_buf(), length as usize, from.offset as usize)
+            .await?;
+        let mut contents = Vec::with_capacity(last - first + 1);
+        for frame in &self.frames[first..=last] {
+            let begin = (frame.offset - from.offset) as usize;
+            let end = begin + frame.compressed as usize;
+            if end > data.len() {
+                return Err(invalid_data(format!("Short read of '{}'", path.display())));
+            }
+            let content = zstd::bulk::decompress(&data[begin..end], frame.size as usize)?;
+            contents.push(Arc::new(content));
+        }
+        *self.last.lock().unwrap() = Some((last, contents.last().unwrap().clone()));
+        Ok(contents)
+    }
+
+    async fn read(
+        &self,
+        backend: &dyn ListingBackend,
+        path: &Path,
+        size: usize,
+        offset: u64,
+    ) -> io::Result<Vec<u8>> {
+        if offset >= self.size || size == 0 {
+            return Ok(Vec::new());
+        }
+        let end = (offset + size as u64).min(self.size);
+        let frame_of = |pos: u64| {
+            self.frames
+                .partition_point(|frame| frame.start + frame.size <= pos)
+        };
+        let (first, last) = (frame_of(offset), frame_of(end - 1));
+        let contents = self.frames(backend, path, first, last).await?;
+        let mut result = Vec::with_capacity((end - offset) as usize);
+        for (frame, content) in self.frames[first..=last].iter().zip(contents) {
+            let from = offset.saturating_sub(frame.start) as usize;
+            let to = ((end - frame.start) as usize).min(content.len());
+            result.extend_from_slice(&content[from.min(to)..to]);
+        }
+        Ok(result)
+    }
+}
+
+/// Seek table of a file, `None` for plain zstd files.
+type Probed = Option<Arc<SeekTable>>;
+
+#[derive(Debug)]
+pub struct Seekable {
+    inner: Arc<dyn ListingBackend>,
+    /// Listing entries of compressed files by their decompressed mount path.
+    known: Mutex<HashMap<PathBuf, (PathBuf, RemoteEntry)>>,
+    /// Seek tables by the remote path of the compressed file along with the
+    /// listing entry they were read for.
+    tables: Mutex<HashMap<PathBuf, (RemoteEntry, Probed)>>,
+}
+
+impl Seekable {
+    pub fn new(inner: Arc<dyn ListingBackend>) -> Self {
+        info!("Decompressing seekable zstd files");
+        Self {
+            inner,
+            known: Mutex::new(HashMap::new()),
+            tables: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Show compressed files listed in the directory `path` by their
+    /// decompressed names and sizes. Seek tables of new or changed files
+    /// are read right away, files without one keep their names.
+    async fn expose(&self, path: &Path, entries: &mut [RemoteEntry]) {
+        let names: HashSet<String> = entries.iter().filter_map(|e| e.name.clone()).collect();
+        let compressed: Vec<(usize, String)> = entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.r#type.as_deref() != Some("directory"))
+            .filter_map(|(i, entry)| {
+                let plain = entry.name.as_deref()?.strip_suffix(".zst")?;
+                if plain.is_empty() || names.contains(plain) {
+                    return None;
+                }
+                Some((i, plain.to_string()))
+            })
+            .collect();
+        let tables = join_all(compressed.iter().map(|(i, _)| {
+            let name = entries[*i].name.as_deref().unwrap_or_default();
+            self.table(path.join(name), &entries[*i])
+        }))
+        .await;
+
+        let mut known = self.known.lock().unwrap();
+        for ((i, plain), table) in compressed.into_iter().zip(tables) {
+            let entry = &mut entries[i];
+            let remote = path.join(entry.name.as_deref().unwrap_or_default());
+            let table = match table {
+                Ok(Some(table)) => table,
+                Ok(None) => {
+                    known.remove(&path.join(&plain));
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Can't read seek table of '{}'. {}", remote.display(), e);
+                    known.remove(&path.join(&plain));
+                    continue;
+                }
+            };
+            known.insert(path.join(&plain), (remote, entry.clone()));
+            entry.name = Some(plain);
+            entry.size = Some(table.size);
+        }
+    }
+
+    fn resolve(&self, path: &Path) -> Option<(PathBuf, RemoteEntry)> {
+        self.known.lock().unwrap().get(path).cloned()
+    }
+
+    /// Seek table of the compressed file at `remote` listed as `entry`, read
+    /// again when the entry changed on the server.
+    async fn table(
+        &self,
+        remote: PathBuf,
+        entry: &RemoteEntry,
+    ) -> io::Result<Probed> {
+        if let Some((listed, table)) = self.tables.lock().unwrap().get(&remote) {
+            if listed == entry {
+                return Ok(table.clone());
+            }
+        }
+        let size = match entry.size {
+            Some(size) => size,
+            None => self.inner.stat(remote.clone()).await?.size.ok_or_else(|| {
+                invalid_data(format!("Size of '{}' is unknown", remote.display()))
+            })?,
+        };
+        let table = SeekTable::fetch(&*self.inner, &remote, size)
+            .await?
+            .map(Arc::new);
+        self.tables
+            .lock()
+            .unwrap()
+            .insert(remote, (entry.clone(), table.clone()));
+        Ok(table)
+    }
+
+    /// Seek table of a file shown decompressed.
+    async fn seek_table(&self, remote: &Path, entry: &RemoteEntry) -> io::Result<Arc<SeekTable>> {
+        self.table(remote.to_path_buf(), entry)
+            .await?
+            .ok_or_else(|| invalid_data(format!("'{}' has no zstd seek table", remote.display())))
+    }
+}
+
+#[async_trait]
+impl ListingBackend for Seekable {
+    async fn list(&self, path: PathBuf) -> io::Result<Vec<RemoteEntry>> {
+        let mut entries = self.inner.list(path.clone()).await?;
+        self.expose(&path, &mut entries).await;
+        Ok(entries)
+    }
+
+    async fn list_changed(&self, path: PathBuf, validators: &Validators) -> io::Result<Changed> {
+        let mut listing = self.inner.list_changed(path.clone(), validators).await?;
+        if let Some(listing) = &mut listing {
+            self.expose(&path, &mut listing.entries).await;
+        }
+        Ok(listing)
+    }
+
+    async fn read(&self, path: PathBuf, size: usize, offset: usize) -> io::Result<Vec<u8>> {
+        match self.resolve(&path) {
+            Some((remote, entry)) => {
+                let table = self.seek_table(&remote, &entry).await?;
+                table.read(&*self.inner, &remote, size, offset as u64).await
+            }
+            None => self.inner.read(path, size, offset).await,
+        }
+    }
+
+    async fn stat(&self, path: PathBuf) -> io::Result<RemoteEntry> {
+        match self.resolve(&path) {
+            Some((remote, mut entry)) => {
+                let table = self.seek_table(&remote, &entry).await?;
+                entry.name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned());
+                entry.size = Some(table.size);
+                Ok(entry)
+            }
+            None => self.inner.stat(path).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::tests::Bytes;
+
+    /// Seekable zstd file with a frame for each of `parts`.
+    fn seekable(parts: &[&[u8]]) -> Vec<u8> {
+        let mut file = Vec::new();
+        let mut table = Vec::new();
+        for part in parts {
+            let frame = zstd::bulk::compress(part, 3).unwrap();
+            table.extend((frame.len() as u32).to_le_bytes());
+            table.extend((part.len() as u32).to_le_bytes());
+            file.extend(frame);
+        }
+        table.extend((parts.len() as u32).to_le_bytes());
+        table.push(0);
+        table.extend(SEEKABLE_MAGIC.to_le_bytes());
+        file.extend(SKIPPABLE_MAGIC.to_le_bytes());
+        file.extend((table.len() as u32).to_le_bytes());
+        file.extend(table);
+        file
+    }
+
+    #[tokio::test]
+    async fn seekable_is_decompressed() {
+        let file = seekable(&[b"hello ", b"seekable ", b"world"]);
+        let zstd = Seekable::new(Arc::new(Bytes::new("song.zst", &file)));
+        let entries = zstd.list(PathBuf::from("/")).await.unwrap();
+        assert_eq!(entries[0].name.as_deref(), Some("song"));
+        assert_eq!(entries[0].size, Some(20));
+        let data = zstd.read(PathBuf::from("/song"), 10, 3).await.unwrap();
+        assert_eq!(data, b"lo seekabl");
+    }
+
+    #[tokio::test]
+    async fn plain_is_left_alone() {
+        let file = zstd::bulk::compress(b"plain zstd", 3).unwrap();
+        let zstd = Seekable::new(Arc::new(Bytes::new("notes.zst", &file)));
+        let entries = zstd.list(PathBuf::from("/")).await.unwrap();
+        assert_eq!(entries[0].name.as_deref(), Some("notes.zst"));
+        assert_eq!(entries[0].size, Some(file.len() as u64));
+        let data = zstd
+            .read(PathBuf::from("/notes.zst"), 100, 0)
+            .await
+            .unwrap();
+        assert_eq!(data, file);
+    }
+}
@@ -0,0 +1,306 @@
+//! Several servers mounted as one merged tree, `server` set to a list.
+//!
+//! Every directory is listed on all servers and the listings are merged.
+//! Directories of the same name are merged too, clashing files are resolved
+//! with `ConflictPolicy`. The server a file came from is remembered so reads
+//! go straight to it.
+
+use crate::client::{file_name, Changed, Listing, ListingBackend, RemoteEntry, Validators};
+use async_trait::async_trait;
+use futures::future::join_all;
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+/// What to do when several servers have a file of the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// Keep the file of the server listed first in the config.
+    #[default]
+    First,
+    /// Keep the file with the most recent mtime.
+    Newest,
+    /// Keep all of them, renaming later ones to `name (N).ext` where `N`
+    /// is the position of the server in the config.
+    Suffix,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "first" => Ok(ConflictPolicy::First),
+            "newest" => Ok(ConflictPolicy::Newest),
+            "suffix" => Ok(ConflictPolicy::Suffix),
+            _ => Err(format!("Unknown conflict policy '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Union {
+    backends: Vec<Arc<dyn ListingBackend>>,
+    policy: ConflictPolicy,
+    /// Server index and remote path of every listed file by its mount path.
+    owners: Mutex<HashMap<PathBuf, (usize, PathBuf)>>,
+}
+
+/// Entry of a merged listing along with the server index and remote name.
+struct Merged {
+    entry: RemoteEntry,
+    server: usize,
+    name: String,
+}
+
+impl Merged {
+    fn is_dir(&self) -> bool {
+        self.entry.r#type.as_deref() == Some("directory")
+    }
+}
+
+impl Union {
+    pub fn new(backends: Vec<Arc<dyn ListingBackend>>, policy: ConflictPolicy) -> Self {
+        info!(
+            "Merging {} servers, resolving conflicts with {:?} policy",
+            backends.len(),
+            policy
+        );
+        Self {
+            backends,
+            policy,
+            owners: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Server index and remote path serving the mount `path`, if known.
+    fn owner(&self, path: &Path) -> Option<(usize, PathBuf)> {
+        self.owners.lock().unwrap().get(path).cloned()
+    }
+
+    /// Merge `listings` of the servers in config order.
+    fn merge(&self, listings: Vec<(usize, Vec<RemoteEntry>)>) -> Vec<Merged> {
+        let mut merged: Vec<Merged> = Vec::new();
+        let mut names: HashMap<String, usize> = HashMap::new();
+        for (server, entries) in listings {
+            for entry in entries {
+                let name = entry.name.clone().unwrap_or_default();
+                let new = Merged {
+                    entry,
+                    server,
+                    name: name.clone(),
+                };
+                let pos = match names.get(&name) {
+                    Some(pos) => *pos,
+                    None => {
+                        names.insert(name, merged.len());
+                        merged.push(new);
+                        continue;
+                    }
+                };
+                let old = &merged[pos];
+                if old.is_dir() && new.is_dir() {
+                    continue;
+                }
+                match self.policy {
+                    ConflictPolicy::First => {}
+                    ConflictPolicy::Newest => {
+                        if new.entry.parse_rfc2822() > old.entry.parse_rfc2822() {
+                            merged[pos] = new;
+                        }
+                    }
+                    ConflictPolicy::Suffix => {
+                        // Directories keep their names so they still merge
+                        // with the same directory on other servers.
+                        let mut renamed = if new.is_dir() {
+                            std::mem::replace(&mut merged[pos], new)
+                        } else {
+                            new
+                        };
+                        let suffixed = suffixed(&renamed.name, renamed.server);
+                        if names.contains_key(&suffixed) {
+                            warn!("Hiding '{}' of server {}", renamed.name, renamed.server + 1);
+                            continue;
+                        }
+                        debug!(
+                            "'{}' of server {} is shown as '{}' next to server {}",
+                            renamed.name,
+                            renamed.server + 1,
+                            suffixed,
+                            merged[pos].server + 1
+                        );
+                        renamed.entry.name = Some(suffixed.clone());
+                        names.insert(suffixed, merged.len());
+                        merged.push(renamed);
+                    }
+                }
+            }
+        }
+        merged
+    }
+
+    /// Run `op` on the server owning `path`, or on each server in turn when
+    /// the owner is not known yet.
+    async fn route<'a, T, F, Fut>(&'a self, path: &Path, op: F) -> io::Result<T>
+    where
+        F: Fn(&'a Arc<dyn ListingBackend>, PathBuf) -> Fut,
+        Fut: std::future::Future<Output = io::Result<T>>,
+    {
+        if let Some((server, remote)) = self.owner(path) {
+            return op(&self.backends[server], remote).await;
+        }
+        let mut error = None;
+        for backend in &self.backends {
+            match op(backend, path.to_path_buf()).await {
+                Ok(result) => return Ok(result),
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        Err(error.unwrap_or_else(|| io::Error::from(io::ErrorKind::NotFound)))
+    }
+
+    /// List `path` on all servers and merge the listings. Servers failing
+    /// for another reason than not having `path` make the listing partial.
+    async fn listing(&self, path: PathBuf) -> io::Result<Listing> {
+        let validators = Validators::default();
+        let results = join_all(
+            self.backends
+                .iter()
+                .map(|backend| backend.list_changed(path.clone(), &validators)),
+        )
+        .await;
+        let mut listings = Vec::new();
+        let mut partial = false;
+        let mut error = None;
+        for (server, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(Some(listing)) => {
+                    partial |= listing.partial;
+                    listings.push((server, listing.entries));
+                }
+                // Never answered without validators.
+                Ok(None) => partial = true,
+                Err(e) => {
+                    debug!(
+                        "Server {} can't list '{}'. {}",
+                        server + 1,
+                        path.display(),
+                        e
+                    );
+                    partial |= e.kind() != io::ErrorKind::NotFound;
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        if listings.is_empty() {
+            return Err(error.unwrap_or_else(|| io::Error::from(io::ErrorKind::NotFound)));
+        }
+
+        let merged = self.merge(listings);
+        let mut owners = self.owners.lock().unwrap();
+        let entries = merged
+            .into_iter()
+            .map(|merged| {
+                if !merged.is_dir() {
+                    let name = merged.entry.name.clone().unwrap_or_default();
+                    owners.insert(path.join(name), (merged.server, path.join(&merged.name)));
+                }
+                merged.entry
+            })
+            .collect();
+        Ok(Listing {
+            entries,
+            validators: Validators::default(),
+            partial,
+        })
+    }
+}
+
+/// `name` renamed after the server at `server` index, `song.mp3` turns into
+/// `song (2).mp3` for the second server.
+fn suffixed(name: &str, server: usize) -> String {
+    let path = Path::new(name);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(ext) if !stem.is_empty() => {
+            format!("{} ({}).{}", stem, server + 1, ext.to_string_lossy())
+        }
+        _ => format!("{} ({})", name, server + 1),
+    }
+}
+
+#[async_trait]
+impl ListingBackend for Union {
+    async fn list(&self, path: PathBuf) -> io::Result<Vec<RemoteEntry>> {
+        Ok(self.listing(path).await?.entries)
+    }
+
+    /// Always lists all servers, validators of single servers can't tell
+    /// whether the merged listing changed.
+    async fn list_changed(&self, path: PathBuf, _: &Validators) -> io::Result<Changed> {
+        Ok(Some(self.listing(path).await?))
+    }
+
+    async fn read(&self, path: PathBuf, size: usize, offset: usize) -> io::Result<Vec<u8>> {
+        self.route(&path, |backend, remote| backend.read(remote, size, offset))
+            .await
+    }
+
+    async fn stat(&self, path: PathBuf) -> io::Result<RemoteEntry> {
+        let mut entry = self
+            .route(&path, |backend, remote| backend.stat(remote))
+            .await?;
+        // Suffixed files keep their mount name.
+        entry.name = file_name(&path);
+        Ok(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::tests::Bytes;
+    use crate::filesystem::tests::Server;
+    use std::sync::atomic::Ordering;
+
+    #[tokio::test]
+    async fn partial_listing() {
+        let server = Arc::new(Server::new(&[("a", b"a")]));
+        let backends: Vec<Arc<dyn ListingBackend>> =
+            vec![server.clone(), Arc::new(Bytes::new("b", b"b"))];
+        let union = Union::new(backends, ConflictPolicy::First);
+        let validators = Validators::default();
+
+        let listing = union
+            .list_changed("/".into(), &validators)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(listing.entries.len(), 2);
+        assert!(!listing.partial);
+
+        // A directory missing on a server is not a failure.
+        let listing = union
+            .list_changed("/dir".into(), &validators)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(listing.entries.len(), 1);
+        assert!(!listing.partial);
+
+        server.down.store(true, Ordering::SeqCst);
+        let listing = union
+            .list_changed("/".into(), &validators)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(listing.entries[0].name.as_deref(), Some("b"));
+        assert!(listing.partial);
+    }
+}